@@ -1,78 +1,296 @@
-use anyhow::Result;
-use rand::seq::SliceRandom;
-use rodio::{Decoder, OutputStream, Sink};
+use crate::settings::AudioSettings;
+use crate::store;
+use crate::tray::UiEvent;
+use anyhow::{anyhow, Result};
+use rand::seq::IteratorRandom;
+use rodio::cpal::traits::HostTrait;
+use rodio::{cpal, Decoder, Device, DeviceTrait, OutputStream, OutputStreamHandle, Sink};
 use std::fs::File;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use tao::event_loop::EventLoopProxy;
 
-pub fn play_random_sound() -> Result<()> {
-    // Get output stream handle
-    let (_stream, stream_handle) = OutputStream::try_default()?;
-    let sink = Sink::try_new(&stream_handle)?;
+/// File extensions rodio can decode with the features we build it with.
+pub const SUPPORTED_FORMATS: &[&str] = &["mp3", "wav"];
 
-    let assets_dir = Path::new("assets");
-    if !assets_dir.exists() {
-        eprintln!(
-            "Assets directory not found at {:?}",
-            std::env::current_dir()?.join(assets_dir)
-        );
-        return Ok(());
-    }
+/// What to do with a sound asked for while another one is still playing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Plays it once the current one is done.
+    Queue,
+    /// Cuts the current one short.
+    Replace,
+    /// Drops it.
+    Ignore,
+}
 
-    let mut sounds: Vec<PathBuf> = Vec::new();
-    let entries = std::fs::read_dir(assets_dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if ext == "mp3" || ext == "wav" {
-                    sounds.push(path);
-                }
-            }
+impl OverlapPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "queue" => Some(Self::Queue),
+            "replace" => Some(Self::Replace),
+            "ignore" => Some(Self::Ignore),
+            _ => None,
         }
     }
+}
 
-    if sounds.is_empty() {
-        println!("No sounds found in assets directory.");
-        return Ok(());
-    }
-
-    let chosen = sounds.choose(&mut rand::thread_rng()).unwrap();
-    println!("Playing: {:?}", chosen);
+/// Requests for the audio worker.
+enum AudioCommand {
+    Play {
+        path: PathBuf,
+        /// Plays even while muted.
+        urgent: bool,
+    },
+    Stop,
+    /// 1.0 is the sound's own volume.
+    SetVolume(f32),
+    SetMuted(bool),
+    /// Output device by name; the system default if unset.
+    SetDevice(Option<String>),
+}
 
-    let file = BufReader::new(File::open(chosen)?);
-    let source = Decoder::new(file)?;
+/// Channel to the audio worker, once started.
+static WORKER: OnceLock<Sender<AudioCommand>> = OnceLock::new();
 
-    sink.append(source);
-    sink.sleep_until_end();
+/// Starts the audio worker; sounds asked for before that are dropped.
+///
+/// The tray hears about it each time the output is opened again, to show where sounds go.
+pub fn start(policy: OverlapPolicy, settings: &AudioSettings, ui: EventLoopProxy<UiEvent>) {
+    let (tx, rx) = mpsc::channel();
+    if WORKER.set(tx).is_err() {
+        return;
+    }
+    let settings = settings.clone();
+    thread::spawn(move || run_worker(rx, policy, settings, ui));
+}
 
-    Ok(())
+/// Names of the output devices currently available.
+pub fn output_devices() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(e) => {
+            eprintln!("Failed to list audio outputs: {}", e);
+            Vec::new()
+        }
+    }
 }
 
-pub fn play_sound_by_hash(target_hash: &str) -> Result<()> {
-    let hashes = crate::sync::get_local_hashes()?;
-    let filename = hashes
-        .iter()
-        .find(|(_, hash)| *hash == target_hash)
-        .map(|(name, _)| name);
+/// Plays a random stored sound; `urgent` ones play even while muted.
+pub fn play_random_sound(urgent: bool) -> Result<()> {
+    let index = store::load_index()?;
+    let Some((hash, entry)) = index.iter().choose(&mut rand::thread_rng()) else {
+        println!("No sounds found in asset store.");
+        return Ok(());
+    };
 
-    if let Some(filename) = filename {
-        let path = Path::new("assets").join(filename);
-        println!("Hashes matched! Playing: {:?}", path);
+    println!("Playing: {}", entry.name());
+    play_file(&store::object_path(hash), urgent)
+}
 
-        let (_stream, stream_handle) = OutputStream::try_default()?;
-        let sink = Sink::try_new(&stream_handle)?;
-        let file = BufReader::new(File::open(path)?);
-        let source = Decoder::new(file)?;
-        sink.append(source);
-        sink.sleep_until_end();
+pub fn play_sound_by_hash(target_hash: &str, urgent: bool) -> Result<()> {
+    // Assets are stored under their hash, so there is nothing to look up.
+    let path = store::object_path(target_hash);
+    if store::is_valid_hash(target_hash) && path.is_file() {
+        println!("Hashes matched! Playing: {}", target_hash);
+        play_file(&path, urgent)
     } else {
         println!(
             "Hash {} not found locally. Playing random fallback.",
             target_hash
         );
-        play_random_sound()?;
+        play_random_sound(urgent)
+    }
+}
+
+/// Stops whatever is playing, and anything queued after it.
+///
+/// Commands reach the worker in the order they were sent, so a stop always silences a sound
+/// asked for before it, even one whose output is still being opened.
+pub fn stop() {
+    let _ = send(AudioCommand::Stop);
+}
+
+/// Sets the playback volume, 1.0 being the sounds' own.
+pub fn set_volume(volume: f32) {
+    let _ = send(AudioCommand::SetVolume(volume));
+}
+
+pub fn set_muted(muted: bool) {
+    let _ = send(AudioCommand::SetMuted(muted));
+}
+
+/// Switches to the named output device, or to the system default.
+pub fn set_device(device: Option<String>) {
+    let _ = send(AudioCommand::SetDevice(device));
+}
+
+/// Hands `path` to the audio worker; it is decoded and played there.
+fn play_file(path: &Path, urgent: bool) -> Result<()> {
+    send(AudioCommand::Play {
+        path: path.to_path_buf(),
+        urgent,
+    })
+}
+
+fn send(command: AudioCommand) -> Result<()> {
+    WORKER
+        .get()
+        .ok_or_else(|| anyhow!("audio worker not started"))?
+        .send(command)
+        .map_err(|_| anyhow!("audio worker stopped"))
+}
+
+/// An open output stream, which can't leave the thread that opened it.
+struct Output {
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+    /// Device actually opened; the system default if unset.
+    device: Option<String>,
+}
+
+impl Output {
+    /// Opens `preferred`, or the system default if it isn't plugged in or fails to open.
+    fn open(preferred: Option<&str>) -> Option<Self> {
+        if let Some(name) = preferred {
+            match find_device(name).map(|device| OutputStream::try_from_device(&device)) {
+                Some(Ok((stream, handle))) => {
+                    println!("Playing sounds on {}", name);
+                    return Some(Self {
+                        _stream: stream,
+                        handle,
+                        device: Some(name.to_string()),
+                    });
+                }
+                Some(Err(e)) => eprintln!("Failed to open audio output {}: {}", name, e),
+                None => eprintln!("Audio output {} not found, using the default one.", name),
+            }
+        }
+        match OutputStream::try_default() {
+            Ok((stream, handle)) => Some(Self {
+                _stream: stream,
+                handle,
+                device: None,
+            }),
+            Err(e) => {
+                eprintln!("Failed to open audio output, sounds are disabled: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Whether this is still the best output for `preferred`: it hasn't been unplugged, and it
+    /// isn't a fallback while the preferred device is back.
+    fn is_current(&self, preferred: Option<&str>) -> bool {
+        match (&self.device, preferred) {
+            (Some(opened), _) => find_device(opened).is_some(),
+            (None, Some(name)) => find_device(name).is_none(),
+            (None, None) => true,
+        }
+    }
+}
+
+/// Plays what it is asked to for as long as the client runs, keeping its output open.
+///
+/// Devices come and go, so the output is checked again before each sound. Commands are handled
+/// one at a time, in order: a stop can't slip in between opening the output and playing.
+fn run_worker(
+    commands: Receiver<AudioCommand>,
+    policy: OverlapPolicy,
+    settings: AudioSettings,
+    ui: EventLoopProxy<UiEvent>,
+) {
+    let open = |preferred: Option<&str>| {
+        let output = Output::open(preferred);
+        let _ = ui.send_event(UiEvent::AudioOutputChanged);
+        output
+    };
+    let mut volume = settings.gain();
+    let mut muted = settings.muted;
+    let mut preferred = settings.device;
+    let mut output = open(preferred.as_deref());
+    let mut sink: Option<Sink> = None;
+
+    for command in commands {
+        match command {
+            AudioCommand::Play { path, urgent } => {
+                if muted && !urgent {
+                    println!("Muted, not playing {}", path.display());
+                    continue;
+                }
+                if !output
+                    .as_ref()
+                    .is_some_and(|output| output.is_current(preferred.as_deref()))
+                {
+                    sink = None;
+                    output = open(preferred.as_deref());
+                }
+                let Some(output) = &output else {
+                    continue;
+                };
+
+                let playing = sink.as_ref().is_some_and(|sink| !sink.empty());
+                if playing && policy == OverlapPolicy::Ignore {
+                    println!("Already playing, ignoring {}", path.display());
+                    continue;
+                }
+                if !playing || policy == OverlapPolicy::Replace {
+                    // Dropping a sink stops its sounds.
+                    sink = new_sink(&output.handle, volume);
+                }
+                let Some(sink) = &sink else {
+                    continue;
+                };
+                match decode(&path) {
+                    Ok(source) => sink.append(source),
+                    Err(e) => eprintln!("Failed to play {}: {}", path.display(), e),
+                }
+            }
+            AudioCommand::Stop => sink = None,
+            AudioCommand::SetVolume(value) => {
+                volume = value;
+                if let Some(sink) = &sink {
+                    sink.set_volume(volume);
+                }
+            }
+            AudioCommand::SetMuted(value) => {
+                muted = value;
+                if muted {
+                    sink = None;
+                }
+            }
+            AudioCommand::SetDevice(device) => {
+                preferred = device;
+                sink = None;
+                output = open(preferred.as_deref());
+            }
+        }
     }
-    Ok(())
+}
+
+fn find_device(name: &str) -> Option<Device> {
+    cpal::default_host()
+        .output_devices()
+        .ok()?
+        .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+}
+
+fn new_sink(output: &OutputStreamHandle, volume: f32) -> Option<Sink> {
+    match Sink::try_new(output) {
+        Ok(sink) => {
+            sink.set_volume(volume);
+            Some(sink)
+        }
+        Err(e) => {
+            eprintln!("Failed to start playback: {}", e);
+            None
+        }
+    }
+}
+
+fn decode(path: &Path) -> Result<Decoder<BufReader<File>>> {
+    Ok(Decoder::new(BufReader::new(File::open(path)?))?)
 }
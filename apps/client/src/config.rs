@@ -0,0 +1,702 @@
+use crate::audio::OverlapPolicy;
+use crate::input::{parse_target, Binding};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use common::{RingKind, RingTarget};
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use url::Url;
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Command line flags; each one overrides its environment variable, which overrides the
+/// configuration file.
+#[derive(Parser, Debug)]
+#[command(version, about = "Sonnerie client")]
+pub struct Cli {
+    /// Configuration file [default: config.toml in the user config directory]
+    #[arg(short, long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Server WebSocket URL; repeat to fall back on other servers
+    #[arg(
+        long = "server",
+        value_name = "URL",
+        env = "SERVER_URL",
+        value_delimiter = ','
+    )]
+    servers: Vec<String>,
+    /// Token the server asks clients for
+    #[arg(long, env = "SERVER_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Name shown to others, remembered for later runs
+    #[arg(long, value_name = "NAME", env = "DISPLAY_NAME")]
+    name: Option<String>,
+    /// Room to listen to; repeat for several
+    #[arg(
+        long = "room",
+        value_name = "ROOM",
+        env = "ROOMS",
+        value_delimiter = ','
+    )]
+    rooms: Vec<String>,
+    /// What our rings are for: doorbell, delivery, lunch, meeting or emergency
+    #[arg(long, env = "RING_KIND")]
+    kind: Option<String>,
+    /// Who our rings are for: `person:<client ID or display name>` or `group:<name>`
+    #[arg(long, env = "RING_TARGET")]
+    target: Option<String>,
+    /// Preset ring message for the tray; repeat for several
+    #[arg(
+        long = "message",
+        value_name = "TEXT",
+        env = "RING_MESSAGES",
+        value_delimiter = '|'
+    )]
+    messages: Vec<String>,
+    /// Global hotkey as `<keys>=<action>`, e.g. `Ctrl+Alt+B=room:reception`; repeat for several
+    #[arg(
+        long = "hotkey",
+        value_name = "BINDING",
+        env = "HOTKEYS",
+        value_delimiter = ';'
+    )]
+    hotkeys: Vec<String>,
+    /// Volume in percent, for this run only
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    volume: Option<u8>,
+    /// What a ring does to a sound still playing: queue, replace or ignore
+    #[arg(long, env = "AUDIO_OVERLAP")]
+    overlap: Option<String>,
+    /// Directory sounds are stored in
+    #[arg(long, value_name = "DIR")]
+    assets: Option<PathBuf>,
+    /// Don't show desktop notifications
+    #[arg(long)]
+    no_notifications: bool,
+}
+
+/// Client settings, from `config.toml` in the user config directory, overridden by the
+/// environment and command line.
+#[derive(Debug)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// Overrides the stored display name.
+    pub display_name: Option<String>,
+    pub ring: RingConfig,
+    pub hotkeys: Vec<Binding>,
+    pub audio: AudioConfig,
+    pub notifications: NotificationConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Tried in turn until one answers.
+    pub urls: Vec<Url>,
+    pub token: Option<String>,
+    /// Rooms to listen to; none means the server's default room.
+    pub rooms: Vec<String>,
+    pub reconnect: ReconnectConfig,
+}
+
+/// How our own rings go out, from the tray and hotkeys.
+#[derive(Debug)]
+pub struct RingConfig {
+    pub kind: RingKind,
+    /// Everyone in the room if unset.
+    pub target: Option<RingTarget>,
+    /// Preset messages offered by the tray.
+    pub messages: Vec<String>,
+    /// Time between two rings from the same hotkey.
+    pub cooldown: Duration,
+}
+
+#[derive(Debug)]
+pub struct AudioConfig {
+    pub assets_dir: PathBuf,
+    pub overlap: OverlapPolicy,
+    /// Percent of the sounds' own volume, until changed from the tray.
+    pub volume: u8,
+    /// Output device, until changed from the tray; the system default if unset.
+    pub device: Option<String>,
+    /// Set from the command line: wins over the tray's saved volume for this run.
+    pub volume_override: Option<u8>,
+}
+
+/// Desktop notifications for rings.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    pub enabled: bool,
+    /// Seconds ring notifications stay up; the desktop's default if unset.
+    pub timeout_secs: Option<u64>,
+    /// Text per ring kind, `{sender}` standing for the ringer's name.
+    pub texts: HashMap<RingKind, String>,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout_secs: None,
+            texts: HashMap::new(),
+        }
+    }
+}
+
+/// Waiting between connection attempts, doubling after each failure.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    pub delay_secs: u64,
+    pub max_delay_secs: u64,
+    /// After the server refused us, which retrying quickly won't fix.
+    pub rejected_delay_secs: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            delay_secs: 5,
+            max_delay_secs: 60,
+            rejected_delay_secs: 60,
+        }
+    }
+}
+
+/// The configuration file as written; see `client.example.toml`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerSection,
+    identity: IdentitySection,
+    ring: RingSection,
+    /// Keys to action, e.g. `"Ctrl+Alt+B" = "room:reception"`.
+    hotkeys: BTreeMap<String, String>,
+    audio: AudioSection,
+    notifications: NotificationConfig,
+    reconnect: ReconnectConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    urls: Vec<String>,
+    token: Option<String>,
+    rooms: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct IdentitySection {
+    display_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RingSection {
+    kind: Option<String>,
+    target: Option<String>,
+    messages: Vec<String>,
+    cooldown_secs: u64,
+}
+
+impl Default for RingSection {
+    fn default() -> Self {
+        Self {
+            kind: None,
+            target: None,
+            messages: Vec::new(),
+            cooldown_secs: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AudioSection {
+    assets_dir: Option<PathBuf>,
+    overlap: Option<String>,
+    volume: Option<u8>,
+    device: Option<String>,
+}
+
+impl Config {
+    /// Reads the configuration file, applies `cli` and the environment on top, and checks the
+    /// result. A missing file is fine unless it was named with `--config`.
+    pub fn load(cli: Cli) -> Result<Self> {
+        let cli = cli.without_blanks();
+        let (path, explicit) = match cli.config {
+            Some(path) => (path, true),
+            None => (default_path()?, false),
+        };
+        let file: ConfigFile = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                println!("Using configuration from {}", path.display());
+                toml::from_str(&text).with_context(|| format!("invalid {}", path.display()))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+                ConfigFile::default()
+            }
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+
+        let urls = or_file(cli.servers, file.server.urls);
+        if urls.is_empty() {
+            bail!(
+                "no server URL: set server.urls in {}, SERVER_URL or --server",
+                path.display()
+            );
+        }
+        let urls = urls
+            .iter()
+            .map(|url| parse_server_url(url))
+            .collect::<Result<Vec<_>>>()?;
+
+        let token = cli.token.or(file.server.token);
+        if let Some(token) = &token {
+            HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| anyhow!("the server token must be printable ASCII"))?;
+        }
+
+        let reconnect = file.reconnect;
+        if reconnect.delay_secs == 0 || reconnect.rejected_delay_secs == 0 {
+            bail!("reconnect delays must be at least one second");
+        }
+        if reconnect.max_delay_secs < reconnect.delay_secs {
+            bail!(
+                "reconnect.max_delay_secs ({}) is shorter than reconnect.delay_secs ({})",
+                reconnect.max_delay_secs,
+                reconnect.delay_secs
+            );
+        }
+
+        let kind = match cli.kind.or(file.ring.kind) {
+            Some(name) => parse_kind(&name)?,
+            None => RingKind::default(),
+        };
+        let target = cli
+            .target
+            .or(file.ring.target)
+            .map(|target| parse_target(&target))
+            .transpose()?;
+
+        let hotkeys = if cli.hotkeys.is_empty() {
+            file.hotkeys
+                .iter()
+                .map(|(keys, action)| format!("{}={}", keys, action))
+                .collect()
+        } else {
+            cli.hotkeys
+        };
+        let hotkeys = if hotkeys.is_empty() {
+            // F9 rang before hotkeys could be configured.
+            vec![Binding::parse("F9=ring")?]
+        } else {
+            hotkeys
+                .iter()
+                .map(|binding| {
+                    Binding::parse(binding).with_context(|| format!("invalid hotkey {}", binding))
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        let overlap = match cli.overlap.or(file.audio.overlap) {
+            Some(name) => OverlapPolicy::from_name(name.trim()).ok_or_else(|| {
+                anyhow!(
+                    "unknown audio overlap policy {}, expected queue, replace or ignore",
+                    name
+                )
+            })?,
+            None => OverlapPolicy::Replace,
+        };
+        let volume = file.audio.volume.unwrap_or(100);
+        if volume > 100 {
+            bail!("audio.volume is {}, at most 100", volume);
+        }
+
+        let mut notifications = file.notifications;
+        if cli.no_notifications {
+            notifications.enabled = false;
+        }
+
+        Ok(Self {
+            server: ServerConfig {
+                urls,
+                token,
+                rooms: non_empty(or_file(cli.rooms, file.server.rooms)),
+                reconnect,
+            },
+            display_name: cli
+                .name
+                .or(file.identity.display_name)
+                .filter(|name| !name.trim().is_empty()),
+            ring: RingConfig {
+                kind,
+                target,
+                messages: non_empty(or_file(cli.messages, file.ring.messages)),
+                cooldown: Duration::from_secs(file.ring.cooldown_secs),
+            },
+            hotkeys,
+            audio: AudioConfig {
+                assets_dir: cli
+                    .assets
+                    .or(file.audio.assets_dir)
+                    .unwrap_or_else(|| PathBuf::from("assets")),
+                overlap,
+                volume,
+                device: file.audio.device,
+                volume_override: cli.volume,
+            },
+            notifications,
+        })
+    }
+}
+
+impl Cli {
+    /// Drops values left blank, like an empty `RING_TARGET=` in `.env`, so they count as unset.
+    fn without_blanks(self) -> Self {
+        let set = |value: Option<String>| value.filter(|value| !value.trim().is_empty());
+        Self {
+            servers: non_empty(self.servers),
+            token: set(self.token),
+            name: set(self.name),
+            rooms: non_empty(self.rooms),
+            kind: set(self.kind),
+            target: set(self.target),
+            messages: non_empty(self.messages),
+            hotkeys: non_empty(self.hotkeys),
+            overlap: set(self.overlap),
+            ..self
+        }
+    }
+}
+
+impl ReconnectConfig {
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.delay_secs)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_secs(self.max_delay_secs)
+    }
+
+    pub fn rejected_delay(&self) -> Duration {
+        Duration::from_secs(self.rejected_delay_secs)
+    }
+}
+
+fn default_path() -> Result<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "sonnerie")
+        .context("Could not determine the user config directory")?;
+    Ok(dirs.config_dir().join(CONFIG_FILE))
+}
+
+/// Lists from the command line or environment replace the file's.
+fn or_file(overrides: Vec<String>, file: Vec<String>) -> Vec<String> {
+    if overrides.is_empty() {
+        file
+    } else {
+        overrides
+    }
+}
+
+fn non_empty(values: Vec<String>) -> Vec<String> {
+    values
+        .into_iter()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn parse_server_url(url: &str) -> Result<Url> {
+    let parsed = Url::parse(url.trim()).with_context(|| format!("invalid server URL {}", url))?;
+    match parsed.scheme() {
+        "ws" | "wss" => Ok(parsed),
+        scheme => bail!(
+            "server URL {} must start with ws:// or wss://, not {}://",
+            url,
+            scheme
+        ),
+    }
+}
+
+fn parse_kind(name: &str) -> Result<RingKind> {
+    RingKind::from_name(name.trim()).ok_or_else(|| {
+        let names: Vec<&str> = RingKind::ALL.iter().map(|kind| kind.name()).collect();
+        anyhow!(
+            "unknown ring kind {}, expected one of {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The environment is shared by every test, so those reading it take turns.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    const VARS: &[&str] = &[
+        "SERVER_URL",
+        "SERVER_TOKEN",
+        "DISPLAY_NAME",
+        "ROOMS",
+        "RING_KIND",
+        "RING_TARGET",
+        "RING_MESSAGES",
+        "HOTKEYS",
+        "AUDIO_OVERLAP",
+    ];
+
+    /// Loads `file` as the configuration file, with `env` set and `args` on the command line.
+    fn load(file: &str, env: &[(&str, &str)], args: &[&str]) -> Result<Config> {
+        let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!(
+            "sonnerie-config-{}-{:?}.toml",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, file).unwrap();
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+        for (var, value) in env {
+            std::env::set_var(var, value);
+        }
+
+        let path_arg = path.to_str().unwrap().to_string();
+        let argv = ["client", "--config", path_arg.as_str()]
+            .into_iter()
+            .chain(args.iter().copied());
+        let config = Cli::try_parse_from(argv)
+            .map_err(anyhow::Error::from)
+            .and_then(Config::load);
+
+        for (var, _) in env {
+            std::env::remove_var(var);
+        }
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    fn urls(config: &Config) -> Vec<&str> {
+        config.server.urls.iter().map(Url::as_str).collect()
+    }
+
+    const FILE: &str = r#"
+        [server]
+        urls = ["ws://file.lan/ws", "wss://backup.lan/ws"]
+        token = "file-token"
+        rooms = ["general", "reception"]
+
+        [identity]
+        display_name = "Alice"
+
+        [ring]
+        kind = "lunch"
+        target = "group:ops"
+        messages = ["On mange ?"]
+        cooldown_secs = 3
+
+        [hotkeys]
+        F10 = "ack"
+
+        [audio]
+        assets_dir = "/tmp/sounds"
+        overlap = "queue"
+        volume = 40
+        device = "USB Audio"
+
+        [notifications]
+        enabled = true
+        timeout_secs = 30
+
+        [reconnect]
+        delay_secs = 2
+        max_delay_secs = 20
+        rejected_delay_secs = 30
+    "#;
+
+    #[test]
+    fn file_settings_apply_without_overrides() {
+        let config = load(FILE, &[], &[]).unwrap();
+
+        assert_eq!(urls(&config), ["ws://file.lan/ws", "wss://backup.lan/ws"]);
+        assert_eq!(config.server.token.as_deref(), Some("file-token"));
+        assert_eq!(config.server.rooms, ["general", "reception"]);
+        assert_eq!(config.server.reconnect.delay(), Duration::from_secs(2));
+        assert_eq!(config.display_name.as_deref(), Some("Alice"));
+        assert_eq!(config.ring.kind, RingKind::Lunch);
+        assert_eq!(
+            config.ring.target,
+            Some(RingTarget::Group("ops".to_string()))
+        );
+        assert_eq!(config.ring.messages, ["On mange ?"]);
+        assert_eq!(config.ring.cooldown, Duration::from_secs(3));
+        assert_eq!(
+            format!("{:?}", config.hotkeys),
+            format!("{:?}", [Binding::parse("F10=ack").unwrap()])
+        );
+        assert_eq!(config.audio.assets_dir, PathBuf::from("/tmp/sounds"));
+        assert_eq!(config.audio.overlap, OverlapPolicy::Queue);
+        assert_eq!(config.audio.volume, 40);
+        assert_eq!(config.audio.device.as_deref(), Some("USB Audio"));
+        assert_eq!(config.audio.volume_override, None);
+        assert!(config.notifications.enabled);
+        assert_eq!(config.notifications.timeout_secs, Some(30));
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let config = load(
+            FILE,
+            &[
+                ("SERVER_URL", "ws://env.lan/ws,ws://env2.lan/ws"),
+                ("SERVER_TOKEN", "env-token"),
+                ("ROOMS", "kitchen"),
+                ("RING_KIND", "delivery"),
+                ("RING_TARGET", "person:Bob"),
+                ("RING_MESSAGES", "Colis|Courrier"),
+                ("HOTKEYS", "F9=ring;F11=mute"),
+                ("AUDIO_OVERLAP", "ignore"),
+                ("DISPLAY_NAME", "Carol"),
+            ],
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(urls(&config), ["ws://env.lan/ws", "ws://env2.lan/ws"]);
+        assert_eq!(config.server.token.as_deref(), Some("env-token"));
+        assert_eq!(config.server.rooms, ["kitchen"]);
+        assert_eq!(config.ring.kind, RingKind::Delivery);
+        assert_eq!(
+            config.ring.target,
+            Some(RingTarget::Person("Bob".to_string()))
+        );
+        assert_eq!(config.ring.messages, ["Colis", "Courrier"]);
+        assert_eq!(config.hotkeys.len(), 2);
+        assert_eq!(config.audio.overlap, OverlapPolicy::Ignore);
+        assert_eq!(config.display_name.as_deref(), Some("Carol"));
+    }
+
+    #[test]
+    fn command_line_overrides_environment_and_file() {
+        let config = load(
+            FILE,
+            &[
+                ("SERVER_URL", "ws://env.lan/ws"),
+                ("ROOMS", "kitchen"),
+                ("RING_KIND", "delivery"),
+            ],
+            &[
+                "--server",
+                "ws://cli.lan/ws",
+                "--room",
+                "lobby",
+                "--room",
+                "hall",
+                "--kind",
+                "emergency",
+                "--volume",
+                "70",
+                "--assets",
+                "/srv/sounds",
+                "--no-notifications",
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(urls(&config), ["ws://cli.lan/ws"]);
+        assert_eq!(config.server.rooms, ["lobby", "hall"]);
+        assert_eq!(config.ring.kind, RingKind::Emergency);
+        assert_eq!(config.audio.volume, 40);
+        assert_eq!(config.audio.volume_override, Some(70));
+        assert_eq!(config.audio.assets_dir, PathBuf::from("/srv/sounds"));
+        assert!(!config.notifications.enabled);
+    }
+
+    #[test]
+    fn blank_environment_values_count_as_unset() {
+        let config = load(
+            FILE,
+            &[("SERVER_URL", ""), ("RING_TARGET", " "), ("ROOMS", ",")],
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(urls(&config), ["ws://file.lan/ws", "wss://backup.lan/ws"]);
+        assert_eq!(
+            config.ring.target,
+            Some(RingTarget::Group("ops".to_string()))
+        );
+        assert_eq!(config.server.rooms, ["general", "reception"]);
+    }
+
+    #[test]
+    fn defaults_need_only_a_server() {
+        let config = load("", &[("SERVER_URL", "ws://localhost:3000/ws")], &[]).unwrap();
+
+        assert_eq!(config.server.token, None);
+        assert!(config.server.rooms.is_empty());
+        assert_eq!(config.ring.kind, RingKind::Doorbell);
+        assert_eq!(config.ring.target, None);
+        assert_eq!(config.ring.cooldown, Duration::from_secs(10));
+        assert_eq!(
+            format!("{:?}", config.hotkeys),
+            format!("{:?}", [Binding::parse("F9=ring").unwrap()])
+        );
+        assert_eq!(config.audio.assets_dir, PathBuf::from("assets"));
+        assert_eq!(config.audio.overlap, OverlapPolicy::Replace);
+        assert_eq!(config.audio.volume, 100);
+        assert!(config.notifications.enabled);
+        assert_eq!(config.server.reconnect.delay(), Duration::from_secs(5));
+        assert_eq!(config.server.reconnect.max_delay(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn mistakes_are_reported() {
+        let server = "[server]\nurls = [\"ws://localhost/ws\"]\n";
+        let invalid = |extra: &str| load(&format!("{}{}", server, extra), &[], &[]).is_err();
+
+        assert!(load("", &[], &[]).is_err());
+        assert!(load("[server]\nurls = [\"http://localhost/ws\"]", &[], &[]).is_err());
+        assert!(load("[server]\nurls = [\"not a url\"]", &[], &[]).is_err());
+        assert!(invalid("token = \"line\\nbreak\"\n"));
+        assert!(invalid("colour = \"red\"\n"));
+        assert!(invalid("[ring]\nkind = \"fire\"\n"));
+        assert!(invalid("[ring]\ntarget = \"group:\"\n"));
+        assert!(invalid("[ring]\ntarget = \"Bob\"\n"));
+        assert!(invalid("[hotkeys]\nF9 = \"shout\"\n"));
+        assert!(invalid("[audio]\noverlap = \"mix\"\n"));
+        assert!(invalid("[audio]\nvolume = 150\n"));
+        assert!(invalid("[reconnect]\ndelay_secs = 0\n"));
+        assert!(invalid(
+            "[reconnect]\ndelay_secs = 30\nmax_delay_secs = 10\n"
+        ));
+        assert!(!invalid(""));
+        assert!(load(server, &[("AUDIO_OVERLAP", "mix")], &[]).is_err());
+        assert!(load(server, &[], &["--volume", "101"]).is_err());
+    }
+
+    #[test]
+    fn a_named_config_file_must_exist() {
+        let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let cli = Cli::try_parse_from([
+            "client",
+            "--config",
+            "/nonexistent/sonnerie.toml",
+            "--server",
+            "ws://localhost/ws",
+        ])
+        .unwrap();
+
+        assert!(Config::load(cli).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const IDENTITY_FILE: &str = "identity.json";
+
+/// Who this client is, kept across restarts so the server recognises us.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Identity {
+    /// Assigned by the server on our first connection.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Issued with `client_id`; without it the server hands out a new identity.
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    pub display_name: String,
+}
+
+impl Identity {
+    /// Remembers the identity the server gave us, if it isn't the one we already have.
+    pub fn adopt(&mut self, client_id: &str, client_secret: &str) -> Result<()> {
+        if self.client_id.as_deref() == Some(client_id)
+            && self.client_secret.as_deref() == Some(client_secret)
+        {
+            return Ok(());
+        }
+        self.client_id = Some(client_id.to_string());
+        self.client_secret = Some(client_secret.to_string());
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = identity_path()?;
+        fs::create_dir_all(path.parent().unwrap()).context("Failed to create config directory")?;
+        fs::write(&path, serde_json::to_vec_pretty(self)?).context("Failed to save identity file")
+    }
+}
+
+/// Loads the stored identity, creating it on first launch.
+///
+/// `requested_name`, from the configuration, overrides the stored name and is remembered.
+pub fn load_or_create(requested_name: Option<&str>) -> Result<Identity> {
+    let path = identity_path()?;
+    let stored: Option<Identity> = match fs::read(&path) {
+        Ok(bytes) => Some(serde_json::from_slice(&bytes).context("Corrupt identity file")?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("Failed to read identity file"),
+    };
+
+    let requested_name = requested_name.map(str::to_string);
+    let identity = match (stored, requested_name) {
+        (Some(identity), None) => return Ok(identity),
+        (Some(identity), Some(name)) if identity.display_name == name => return Ok(identity),
+        (Some(identity), Some(name)) => Identity {
+            display_name: name,
+            ..identity
+        },
+        (None, name) => Identity {
+            client_id: None,
+            client_secret: None,
+            display_name: name.unwrap_or_else(default_display_name),
+        },
+    };
+
+    identity.save()?;
+    Ok(identity)
+}
+
+fn identity_path() -> Result<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "sonnerie")
+        .context("Could not determine the user config directory")?;
+    Ok(dirs.config_dir().join(IDENTITY_FILE))
+}
+
+fn default_display_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "Anonyme".to_string())
+}
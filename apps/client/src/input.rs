@@ -1,34 +1,363 @@
-use anyhow::Result;
-use common::WsMessage;
+use crate::tray::UiEvent;
+use anyhow::{anyhow, bail, Result};
+use common::RingTarget;
 use rdev::{listen, EventType, Key};
+use std::collections::{HashMap, HashSet};
 use std::thread;
-use tokio::sync::mpsc;
-use uuid::Uuid;
+use std::time::{Duration, Instant};
+use tao::event_loop::EventLoopProxy;
 
-pub fn start_global_listener(tx_ws: mpsc::Sender<WsMessage>, my_uuid: Uuid) -> Result<()> {
+/// What a hotkey does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HotkeyAction {
+    /// Rings where the tray does: the first room we listen to, for the configured target.
+    Ring,
+    RingRoom(String),
+    /// Rings a person or group in the first room we listen to.
+    RingTarget(RingTarget),
+    /// Answers the ring we are hearing.
+    Acknowledge,
+    ToggleMute,
+}
+
+impl HotkeyAction {
+    /// Parses `ring`, `room:<name>`, `person:<client ID or display name>`, `group:<name>`, `ack`
+    /// or `mute`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let action = match text.split_once(':') {
+            Some(("room", room)) => Self::RingRoom(room.trim().to_string()),
+            Some(("person" | "group", _)) => Self::RingTarget(parse_target(text)?),
+            Some(_) => bail!("unknown action {}", text),
+            None => match text {
+                "ring" => Self::Ring,
+                "ack" => Self::Acknowledge,
+                "mute" => Self::ToggleMute,
+                _ => bail!("unknown action {}", text),
+            },
+        };
+        match &action {
+            Self::RingRoom(name) if name.is_empty() => bail!("{} names no one", text),
+            _ => Ok(action),
+        }
+    }
+
+    fn is_ring(&self) -> bool {
+        matches!(self, Self::Ring | Self::RingRoom(_) | Self::RingTarget(_))
+    }
+}
+
+/// Parses who a ring is for, `person:<client ID or display name>` or `group:<name>`, the same
+/// way in hotkeys and in the `[ring]` settings.
+pub fn parse_target(text: &str) -> Result<RingTarget> {
+    let text = text.trim();
+    let target = match text.split_once(':') {
+        Some(("person", person)) => RingTarget::Person(person.trim().to_string()),
+        Some(("group", group)) => RingTarget::Group(group.trim().to_string()),
+        _ => bail!(
+            "ring target {:?} must be person:<name> or group:<name>",
+            text
+        ),
+    };
+    match &target {
+        RingTarget::Person(name) | RingTarget::Group(name) if name.is_empty() => {
+            bail!("ring target {:?} names no one", text)
+        }
+        _ => Ok(target),
+    }
+}
+
+/// Modifier keys held with a hotkey's key, whichever side of the keyboard they are on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Modifiers {
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+    meta: bool,
+}
+
+impl Modifiers {
+    fn held(pressed: &HashSet<Key>) -> Self {
+        let any = |keys: &[Key]| keys.iter().any(|key| pressed.contains(key));
+        Self {
+            ctrl: any(&[Key::ControlLeft, Key::ControlRight]),
+            alt: any(&[Key::Alt, Key::AltGr]),
+            shift: any(&[Key::ShiftLeft, Key::ShiftRight]),
+            meta: any(&[Key::MetaLeft, Key::MetaRight]),
+        }
+    }
+}
+
+/// A key along with the exact modifiers that must be held with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    modifiers: Modifiers,
+    key: Key,
+}
+
+impl Hotkey {
+    /// Parses combinations like `F9` or `Ctrl+Alt+B`, ignoring case.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parts: Vec<String> = text
+            .split('+')
+            .map(|part| part.trim().to_ascii_lowercase())
+            .collect();
+        let key = parts.pop().unwrap_or_default();
+        let key = parse_key(&key).ok_or_else(|| anyhow!("unknown key {:?} in {}", key, text))?;
+
+        let mut modifiers = Modifiers::default();
+        for part in &parts {
+            let modifier = match part.as_str() {
+                "ctrl" | "control" => &mut modifiers.ctrl,
+                "alt" => &mut modifiers.alt,
+                "shift" => &mut modifiers.shift,
+                "super" | "meta" | "win" | "cmd" => &mut modifiers.meta,
+                _ => bail!("unknown modifier {:?} in {}", part, text),
+            };
+            *modifier = true;
+        }
+        Ok(Self { modifiers, key })
+    }
+}
+
+/// A hotkey and what it does.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    hotkey: Hotkey,
+    action: HotkeyAction,
+}
+
+impl Binding {
+    /// Parses `<hotkey>=<action>`, e.g. `Ctrl+Alt+B=room:reception`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let (hotkey, action) = text
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <hotkey>=<action>, got {}", text))?;
+        Ok(Self {
+            hotkey: Hotkey::parse(hotkey)?,
+            action: HotkeyAction::parse(action)?,
+        })
+    }
+}
+
+/// Watches the keyboard system-wide and hands the actions of pressed hotkeys to the tray.
+///
+/// A hotkey fires once per press, only with exactly its modifiers held: `F9` doesn't fire for
+/// `Ctrl+F9`, nor again while F9 auto-repeats. Ring hotkeys then wait out `ring_cooldown`; the
+/// server has its own cooldown on top.
+pub fn start_global_listener(
+    bindings: Vec<Binding>,
+    ring_cooldown: Duration,
+    ui: EventLoopProxy<UiEvent>,
+) -> Result<()> {
     // Run rdev listener in a dedicated thread (blocking)
     thread::spawn(move || {
-        let mut last_trigger: Option<std::time::Instant> = None;
-        if let Err(error) = listen(move |event| {
-            if let EventType::KeyPress(key) = event.event_type {
-                if key == Key::F9 {
-                    let now = std::time::Instant::now();
-                    if let Some(last) = last_trigger {
-                        if now.duration_since(last) < std::time::Duration::from_secs(10) {
-                            println!("Cooldown active (10s). Ignoring F9.");
-                            return;
+        let mut pressed: HashSet<Key> = HashSet::new();
+        let mut last_rings: HashMap<usize, Instant> = HashMap::new();
+        if let Err(error) = listen(move |event| match event.event_type {
+            EventType::KeyPress(key) => {
+                if !pressed.insert(key) {
+                    return;
+                }
+                let hotkey = Hotkey {
+                    modifiers: Modifiers::held(&pressed),
+                    key,
+                };
+                for (index, binding) in bindings.iter().enumerate() {
+                    if binding.hotkey != hotkey {
+                        continue;
+                    }
+                    if binding.action.is_ring() {
+                        let now = Instant::now();
+                        if last_rings
+                            .get(&index)
+                            .is_some_and(|last| now.duration_since(*last) < ring_cooldown)
+                        {
+                            println!(
+                                "Cooldown active ({}s). Ignoring {:?}.",
+                                ring_cooldown.as_secs(),
+                                binding.action
+                            );
+                            continue;
                         }
+                        last_rings.insert(index, now);
                     }
-                    last_trigger = Some(now);
-
-                    println!("F9 pressed! Sending ring_bell...");
-                    let msg = WsMessage::ring_bell(Some(my_uuid.to_string()));
-                    let _ = tx_ws.blocking_send(msg);
+                    println!("Hotkey pressed: {:?}", binding.action);
+                    let _ = ui.send_event(UiEvent::Hotkey(binding.action.clone()));
                 }
             }
+            EventType::KeyRelease(key) => {
+                pressed.remove(&key);
+            }
+            _ => {}
         }) {
             eprintln!("Error: {:?}", error);
         }
     });
     Ok(())
 }
+
+fn parse_key(name: &str) -> Option<Key> {
+    let key = match name {
+        "a" => Key::KeyA,
+        "b" => Key::KeyB,
+        "c" => Key::KeyC,
+        "d" => Key::KeyD,
+        "e" => Key::KeyE,
+        "f" => Key::KeyF,
+        "g" => Key::KeyG,
+        "h" => Key::KeyH,
+        "i" => Key::KeyI,
+        "j" => Key::KeyJ,
+        "k" => Key::KeyK,
+        "l" => Key::KeyL,
+        "m" => Key::KeyM,
+        "n" => Key::KeyN,
+        "o" => Key::KeyO,
+        "p" => Key::KeyP,
+        "q" => Key::KeyQ,
+        "r" => Key::KeyR,
+        "s" => Key::KeyS,
+        "t" => Key::KeyT,
+        "u" => Key::KeyU,
+        "v" => Key::KeyV,
+        "w" => Key::KeyW,
+        "x" => Key::KeyX,
+        "y" => Key::KeyY,
+        "z" => Key::KeyZ,
+        "0" => Key::Num0,
+        "1" => Key::Num1,
+        "2" => Key::Num2,
+        "3" => Key::Num3,
+        "4" => Key::Num4,
+        "5" => Key::Num5,
+        "6" => Key::Num6,
+        "7" => Key::Num7,
+        "8" => Key::Num8,
+        "9" => Key::Num9,
+        "f1" => Key::F1,
+        "f2" => Key::F2,
+        "f3" => Key::F3,
+        "f4" => Key::F4,
+        "f5" => Key::F5,
+        "f6" => Key::F6,
+        "f7" => Key::F7,
+        "f8" => Key::F8,
+        "f9" => Key::F9,
+        "f10" => Key::F10,
+        "f11" => Key::F11,
+        "f12" => Key::F12,
+        "space" => Key::Space,
+        "enter" | "return" => Key::Return,
+        "tab" => Key::Tab,
+        "esc" | "escape" => Key::Escape,
+        "backspace" => Key::Backspace,
+        "insert" => Key::Insert,
+        "delete" => Key::Delete,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "up" => Key::UpArrow,
+        "down" => Key::DownArrow,
+        "left" => Key::LeftArrow,
+        "right" => Key::RightArrow,
+        "pause" => Key::Pause,
+        "printscreen" => Key::PrintScreen,
+        "scrolllock" => Key::ScrollLock,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hotkey(key: Key, ctrl: bool, alt: bool, shift: bool, meta: bool) -> Hotkey {
+        Hotkey {
+            modifiers: Modifiers {
+                ctrl,
+                alt,
+                shift,
+                meta,
+            },
+            key,
+        }
+    }
+
+    #[test]
+    fn hotkey_parses_keys_and_modifiers_ignoring_case_and_spaces() {
+        assert_eq!(
+            Hotkey::parse("F9").unwrap(),
+            hotkey(Key::F9, false, false, false, false)
+        );
+        assert_eq!(
+            Hotkey::parse("ctrl + ALT+b").unwrap(),
+            hotkey(Key::KeyB, true, true, false, false)
+        );
+        assert_eq!(
+            Hotkey::parse("Shift+Super+PageUp").unwrap(),
+            hotkey(Key::PageUp, false, false, true, true)
+        );
+        assert_eq!(
+            Hotkey::parse("Control+Win+return").unwrap(),
+            hotkey(Key::Return, true, false, false, true)
+        );
+        assert_eq!(
+            Hotkey::parse("Cmd+1").unwrap(),
+            Hotkey::parse("meta+1").unwrap()
+        );
+    }
+
+    #[test]
+    fn hotkey_refuses_unknown_keys_and_modifiers() {
+        assert!(Hotkey::parse("").is_err());
+        assert!(Hotkey::parse("F13").is_err());
+        assert!(Hotkey::parse("Ctrl+").is_err());
+        assert!(Hotkey::parse("Hyper+A").is_err());
+        // Modifiers go before the key.
+        assert!(Hotkey::parse("A+Ctrl").is_err());
+    }
+
+    #[test]
+    fn binding_parses_hotkey_and_action() {
+        let binding = Binding::parse("Ctrl+Alt+B=room:reception").unwrap();
+        assert_eq!(binding.hotkey, hotkey(Key::KeyB, true, true, false, false));
+        assert_eq!(
+            binding.action,
+            HotkeyAction::RingRoom("reception".to_string())
+        );
+
+        let binding = Binding::parse(" F10 = ack ").unwrap();
+        assert_eq!(binding.hotkey, hotkey(Key::F10, false, false, false, false));
+        assert_eq!(binding.action, HotkeyAction::Acknowledge);
+    }
+
+    #[test]
+    fn binding_parses_every_action() {
+        let action = |text: &str| Binding::parse(&format!("F9={}", text)).unwrap().action;
+
+        assert_eq!(action("ring"), HotkeyAction::Ring);
+        assert_eq!(action("mute"), HotkeyAction::ToggleMute);
+        assert_eq!(
+            action("person: Bob"),
+            HotkeyAction::RingTarget(RingTarget::Person("Bob".to_string()))
+        );
+        assert_eq!(
+            action("group:ops"),
+            HotkeyAction::RingTarget(RingTarget::Group("ops".to_string()))
+        );
+    }
+
+    #[test]
+    fn binding_refuses_malformed_entries() {
+        assert!(Binding::parse("F9").is_err());
+        assert!(Binding::parse("F9=").is_err());
+        assert!(Binding::parse("F9=shout").is_err());
+        assert!(Binding::parse("F9=room:").is_err());
+        assert!(Binding::parse("F9=person: ").is_err());
+        assert!(Binding::parse("F9=channel:ops").is_err());
+        assert!(Binding::parse("Foo=ring").is_err());
+    }
+}
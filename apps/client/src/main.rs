@@ -1,44 +1,90 @@
 mod audio;
+mod config;
+mod identity;
 mod input;
 mod network;
+mod settings;
+mod store;
 mod sync;
+mod tray;
 
-use crate::input::start_global_listener;
+use crate::config::{Cli, Config};
+use crate::input::{start_global_listener, HotkeyAction};
 use crate::network::run_ws_client;
-use common::WsMessage;
-use tao::event_loop::{ControlFlow, EventLoop};
-use tao::platform::run_return::EventLoopExtRunReturn; // Helper if needed, but standard run is usually fine
+use crate::settings::AudioSettings;
+use crate::tray::{Tray, TrayAction, UiEvent};
+use clap::Parser;
+use common::{RingBell, Status, WsMessage};
+use tao::event::Event;
+use tao::event_loop::{ControlFlow, EventLoopBuilder};
 use tokio::sync::mpsc;
-use tray_icon::{
-    menu::{Menu, MenuItem, PredefinedMenuItem},
-    TrayIconBuilder,
-};
-use uuid::Uuid;
 
 fn main() {
     dotenv::dotenv().ok();
     env_logger::init();
 
+    // Parsed before anything starts, so `--help` and bad flags exit right away.
+    let cli = Cli::parse();
     println!("Starting Sonnerie Client (Tray Mode)");
+    let config = match Config::load(cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    store::set_dir(config.audio.assets_dir.clone());
 
-    let event_loop = EventLoop::new();
+    let event_loop = EventLoopBuilder::<UiEvent>::with_user_event().build();
+    let ui = event_loop.create_proxy();
 
     // -- System Tray Setup --
-    let tray_menu = Menu::new();
-    let quit_i = MenuItem::new("Quitter", true, None);
-    tray_menu.append(&quit_i).unwrap();
-
-    let mut _tray_icon = Some(
-        TrayIconBuilder::new()
-            .with_menu(Box::new(tray_menu))
-            .with_tooltip("Sonnerie Client")
-            .with_icon(load_icon())
-            .build()
-            .unwrap(),
+    // The tray's saved choices win over the configuration, but not over `--volume`.
+    let mut audio_settings = AudioSettings::load()
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to load audio settings, using the configured ones: {:#}",
+                e
+            );
+            None
+        })
+        .unwrap_or_else(|| AudioSettings {
+            device: config.audio.device.clone(),
+            volume: config.audio.volume,
+            muted: false,
+        });
+    if let Some(volume) = config.audio.volume_override {
+        audio_settings.volume = volume;
+    }
+    let mut tray = Some(Tray::new(
+        Status::default(),
+        &config.ring.messages,
+        &audio_settings,
+        &audio::output_devices(),
+    ));
+
+    let identity = match identity::load_or_create(config.display_name.as_deref()) {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("Failed to load identity: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "My Client ID: {} ({})",
+        identity
+            .client_id
+            .as_deref()
+            .unwrap_or("assigned on first connection"),
+        identity.display_name
     );
 
-    let my_uuid = Uuid::new_v4();
-    println!("My Client UUID: {}", my_uuid);
+    audio::start(config.audio.overlap, &audio_settings, ui.clone());
+
+    let rooms = &config.server.rooms;
+    if !rooms.is_empty() {
+        println!("Rooms: {}", rooms.join(", "));
+    }
 
     // Channel for input thread -> async ws task
     let (tx, rx) = mpsc::channel::<WsMessage>(100);
@@ -46,10 +92,16 @@ fn main() {
     // -- Start Logic Threads --
 
     // 1. Global Input Listener (Blocking)
-    // We clone tx because start_global_listener takes ownership or needs a clone
-    let tx_clone = tx.clone();
     // Note: start_global_listener spawns its own thread internally, so we just call it.
-    if let Err(e) = start_global_listener(tx_clone, my_uuid) {
+    // Rings from the tray and hotkeys go to the first room we listen to, or the server's
+    // default one.
+    let ring_target = config.ring.target.clone();
+    if let Some(target) = &ring_target {
+        println!("Rings go to {:?}", target);
+    }
+    let kind = config.ring.kind;
+    let ring_room = rooms.first().cloned();
+    if let Err(e) = start_global_listener(config.hotkeys, config.ring.cooldown, ui.clone()) {
         eprintln!("Failed to start global listener: {}", e);
     }
 
@@ -60,7 +112,13 @@ fn main() {
             .build()
             .unwrap();
 
-        rt.block_on(run_ws_client(my_uuid, rx));
+        rt.block_on(run_ws_client(
+            config.server,
+            config.notifications,
+            identity,
+            ui,
+            rx,
+        ));
     });
 
     // -- Run Event Loop (Main Thread) --
@@ -68,35 +126,135 @@ fn main() {
     let menu_channel = tray_icon::menu::MenuEvent::receiver();
     // let tray_channel = tray_icon::TrayIconEvent::receiver();
 
-    event_loop.run(move |_event, _, control_flow| {
+    // Ring the tray's "J'arrive" answers, as last reported by the network thread.
+    let mut pending_ring: Option<String> = None;
+    // Our own ring the tray's "Annuler ma sonnerie" cancels.
+    let mut own_ring: Option<String> = None;
+
+    event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
 
+        let Some(current) = tray.as_mut() else {
+            return;
+        };
+        match event {
+            Event::UserEvent(UiEvent::Presence(people)) => current.show_presence(&people),
+            Event::UserEvent(UiEvent::RecentRings(rings)) => current.show_recent_rings(&rings),
+            Event::UserEvent(UiEvent::PendingRing(ring_id)) => {
+                current.show_pending_ring(ring_id.is_some());
+                pending_ring = ring_id;
+            }
+            Event::UserEvent(UiEvent::OwnRing(ring_id)) => {
+                current.show_own_ring(ring_id.is_some());
+                own_ring = ring_id;
+            }
+            Event::UserEvent(UiEvent::Acknowledge(ring_id)) => {
+                println!("Answering ring {}", ring_id);
+                let _ = tx.blocking_send(WsMessage::ack(ring_id));
+            }
+            Event::UserEvent(UiEvent::AudioOutputChanged) => {
+                current.show_devices(&audio::output_devices(), &audio_settings);
+            }
+            Event::UserEvent(UiEvent::Hotkey(action)) => match action {
+                HotkeyAction::Ring => {
+                    let ring = WsMessage::ring_bell(ring_room.clone(), ring_target.clone(), kind);
+                    let _ = tx.blocking_send(ring);
+                }
+                HotkeyAction::RingRoom(room) => {
+                    let _ = tx.blocking_send(WsMessage::ring_bell(Some(room), None, kind));
+                }
+                HotkeyAction::RingTarget(target) => {
+                    let ring = WsMessage::ring_bell(ring_room.clone(), Some(target), kind);
+                    let _ = tx.blocking_send(ring);
+                }
+                HotkeyAction::Acknowledge => {
+                    if let Some(ring_id) = pending_ring.take() {
+                        println!("Answering ring {}", ring_id);
+                        let _ = tx.blocking_send(WsMessage::ack(ring_id));
+                    }
+                }
+                HotkeyAction::ToggleMute => {
+                    let muted = !audio_settings.muted;
+                    set_muted(current, &mut audio_settings, muted);
+                }
+            },
+            _ => {}
+        }
+
         if let Ok(event) = menu_channel.try_recv() {
-            if event.id == quit_i.id() {
-                // cleanup
-                _tray_icon.take();
-                *control_flow = ControlFlow::Exit;
-                std::process::exit(0);
+            match current.action_for(&event) {
+                Some(TrayAction::Acknowledge) => {
+                    if let Some(ring_id) = pending_ring.take() {
+                        println!("Answering ring {}", ring_id);
+                        let _ = tx.blocking_send(WsMessage::ack(ring_id));
+                    }
+                }
+                Some(TrayAction::CancelRing) => {
+                    if let Some(ring_id) = own_ring.take() {
+                        println!("Cancelling ring {}", ring_id);
+                        let _ = tx.blocking_send(WsMessage::cancel_ring(ring_id));
+                    }
+                }
+                Some(TrayAction::Ring(message)) => {
+                    println!("Ringing with message: {}", message);
+                    let ring = WsMessage::RingBell(RingBell {
+                        room: ring_room.clone(),
+                        target: ring_target.clone(),
+                        kind,
+                        message: Some(message),
+                        ..Default::default()
+                    });
+                    let _ = tx.blocking_send(ring);
+                }
+                Some(TrayAction::SetStatus(status)) => {
+                    current.show_status(status);
+                    println!("Status set to {:?}", status);
+                    let _ = tx.blocking_send(WsMessage::set_status(status));
+                }
+                Some(TrayAction::SetMuted(muted)) => {
+                    set_muted(current, &mut audio_settings, muted);
+                }
+                Some(TrayAction::SetVolume(volume)) => {
+                    println!("Volume set to {}%", volume);
+                    audio_settings.volume = volume;
+                    audio::set_volume(audio_settings.gain());
+                    save_audio_settings(current, &audio_settings);
+                }
+                Some(TrayAction::SetDevice(device)) => {
+                    println!(
+                        "Audio output set to {}",
+                        device.as_deref().unwrap_or("the default one")
+                    );
+                    audio_settings.device = device.clone();
+                    audio::set_device(device);
+                    save_audio_settings(current, &audio_settings);
+                }
+                Some(TrayAction::RefreshDevices) => {
+                    current.show_devices(&audio::output_devices(), &audio_settings);
+                }
+                Some(TrayAction::Quit) => {
+                    // cleanup
+                    tray.take();
+                    *control_flow = ControlFlow::Exit;
+                    std::process::exit(0);
+                }
+                None => {}
             }
         }
     });
 }
 
-fn load_icon() -> tray_icon::Icon {
-    // Generate a simple 32x32 green/red icon manually since we might not have a file handy immediately
-    // or use a helper to load from file. For now, generated pixel buffer.
-    let (icon_rgba, icon_width, icon_height) = {
-        let width = 32;
-        let height = 32;
-        let mut rgba = Vec::with_capacity((width * height * 4) as usize);
-        for i in 0..width * height {
-            // Greenish color
-            rgba.push(0);
-            rgba.push(255);
-            rgba.push(0);
-            rgba.push(255);
-        }
-        (rgba, width, height)
-    };
-    tray_icon::Icon::from_rgba(icon_rgba, icon_width, icon_height).expect("Failed to open icon")
+fn set_muted(tray: &Tray, settings: &mut AudioSettings, muted: bool) {
+    println!("Sound {}", if muted { "muted" } else { "unmuted" });
+    settings.muted = muted;
+    audio::set_muted(muted);
+    save_audio_settings(tray, settings);
+}
+
+/// Shows the audio settings in the tray and keeps them for the next start.
+fn save_audio_settings(tray: &Tray, settings: &AudioSettings) {
+    tray.show_audio(settings);
+    if let Err(e) = settings.save() {
+        eprintln!("Failed to save audio settings: {:#}", e);
+    }
 }
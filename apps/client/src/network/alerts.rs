@@ -0,0 +1,291 @@
+use crate::audio;
+use crate::config::NotificationConfig;
+use crate::tray::UiEvent;
+use common::{Acknowledged, RingBell, RingCancelled, RingKind, RingSent, RingTarget};
+use notify_rust::{Notification, Timeout};
+use std::time::Duration;
+use tao::event_loop::EventLoopProxy;
+
+/// Notification action answering a ring.
+const ACK_ACTION: &str = "ack";
+
+/// How long the notice replacing a cancelled ring's notification stays up.
+const CANCELLED_NOTICE_TIMEOUT: Timeout = Timeout::Milliseconds(5000);
+
+/// A ring we were alerted to and haven't seen answered yet.
+struct PendingRing {
+    ring_id: String,
+    /// Set where the platform lets us update the notification afterwards.
+    notification_id: Option<u32>,
+}
+
+/// Desktop notifications for rings and for answers to them.
+pub struct Alerts {
+    pending: Option<PendingRing>,
+    /// Our own latest ring, while it can still be cancelled.
+    own: Option<String>,
+    config: NotificationConfig,
+    ui: EventLoopProxy<UiEvent>,
+}
+
+impl Alerts {
+    pub fn new(ui: EventLoopProxy<UiEvent>, config: NotificationConfig) -> Self {
+        Self {
+            pending: None,
+            own: None,
+            config,
+            ui,
+        }
+    }
+
+    /// Notifies about a ring we are hearing, offering to answer it.
+    pub fn ring(&mut self, ring: &RingBell) {
+        // Names and messages come from other people: keep them from passing for markup.
+        let sender = ring.sender_name.as_deref().map(escape_markup);
+        let mut body = match self.config.texts.get(&ring.kind) {
+            Some(text) => text.replace("{sender}", sender.as_deref().unwrap_or("On")),
+            None => ring_text(ring.kind, sender.as_deref()),
+        };
+        if let Some(message) = &ring.message {
+            body = format!("{}\n« {} »", body, escape_markup(message));
+        }
+        let mut summary = match (&ring.target, &ring.room) {
+            (Some(RingTarget::Group(group)), _) => format!("Sonnerie — {}", group),
+            (Some(RingTarget::Person(_)), _) => "Sonnerie — pour vous".to_string(),
+            (None, Some(room)) => format!("Sonnerie — {}", room),
+            (None, None) => "Sonnerie".to_string(),
+        };
+        if ring.attempt > 0 {
+            summary = format!("{} (relance {})", summary, ring.attempt);
+        }
+
+        let mut notification = Notification::new();
+        notification
+            .summary(&summary)
+            .body(&body)
+            .appname("Sonnerie");
+        if ring.ring_id.is_some() {
+            notification.action(ACK_ACTION, "J'arrive");
+        }
+        if let Some(secs) = self.config.timeout_secs {
+            notification.timeout(Duration::from_secs(secs));
+        }
+        #[cfg(all(unix, not(target_os = "macos")))]
+        notification.urgency(match ring.kind.urgency() {
+            common::Urgency::Low => notify_rust::Urgency::Low,
+            common::Urgency::Normal => notify_rust::Urgency::Normal,
+            common::Urgency::Critical => notify_rust::Urgency::Critical,
+        });
+        // A re-ring takes the place of the notification we showed for the first attempt.
+        let previous = self
+            .pending
+            .as_ref()
+            .filter(|pending| ring.ring_id.as_deref() == Some(pending.ring_id.as_str()))
+            .and_then(|pending| pending.notification_id);
+        if let Some(id) = previous {
+            notification.id(id);
+        }
+        let notification_id = self.notify(&notification, ring.ring_id.clone());
+
+        self.pending = ring.ring_id.clone().map(|ring_id| PendingRing {
+            ring_id,
+            notification_id,
+        });
+        self.publish_pending();
+    }
+
+    /// Our own ring went out; it can be cancelled until someone answers it.
+    pub fn sent(&mut self, sent: &RingSent) {
+        println!(
+            "Rang room {} for {} listeners ({})",
+            sent.room, sent.listeners, sent.ring_id
+        );
+        self.own = Some(sent.ring_id.clone());
+        self.publish_own();
+    }
+
+    /// A ring was cancelled: stop it if we were hearing it and take its notification back.
+    pub fn cancelled(&mut self, cancelled: &RingCancelled) {
+        if self.own.as_deref() == Some(cancelled.ring_id.as_str()) {
+            self.own = None;
+            self.publish_own();
+        }
+        let Some(pending) = self
+            .pending
+            .take_if(|pending| pending.ring_id == cancelled.ring_id)
+        else {
+            return;
+        };
+        println!("{} cancelled the ring.", cancelled.by);
+        audio::stop();
+        self.publish_pending();
+
+        // Notifications can only be taken back by replacing them with a notice that soon expires.
+        if let Some(id) = pending.notification_id {
+            let mut notification = Notification::new();
+            notification
+                .summary("Sonnerie")
+                .body(&format!(
+                    "🔕 Sonnerie annulée par {}",
+                    escape_markup(&cancelled.by)
+                ))
+                .appname("Sonnerie")
+                .timeout(CANCELLED_NOTICE_TIMEOUT)
+                .id(id);
+            self.notify(&notification, None);
+        }
+    }
+
+    /// Someone answered a ring: stop ringing if it was ours to hear, and say who is coming.
+    pub fn acknowledged(&mut self, ack: &Acknowledged, my_id: &str) {
+        let pending = self
+            .pending
+            .take_if(|pending| pending.ring_id == ack.ring_id);
+        if pending.is_some() {
+            audio::stop();
+            self.publish_pending();
+        }
+        if self.own.as_deref() == Some(ack.ring_id.as_str()) {
+            self.own = None;
+            self.publish_own();
+        }
+        if ack.client_id == my_id {
+            println!("Told everyone we are coming.");
+            return;
+        }
+
+        println!("{} is answering the ring.", ack.display_name);
+        let mut notification = Notification::new();
+        notification
+            .summary("Sonnerie")
+            .body(&format!("🏃 {} arrive !", escape_markup(&ack.display_name)))
+            .appname("Sonnerie");
+        // Replaces the ring's own notification where possible.
+        if let Some(id) = pending.and_then(|pending| pending.notification_id) {
+            notification.id(id);
+        }
+        self.notify(&notification, None);
+    }
+
+    /// Forgets the pending and own rings, e.g. once the connection is lost.
+    pub fn clear(&mut self) {
+        if self.pending.take().is_some() {
+            self.publish_pending();
+        }
+        if self.own.take().is_some() {
+            self.publish_own();
+        }
+    }
+
+    /// Shows `notification` unless notifications are turned off.
+    fn notify(&self, notification: &Notification, ring_id: Option<String>) -> Option<u32> {
+        if !self.config.enabled {
+            return None;
+        }
+        show(notification, ring_id, &self.ui)
+    }
+
+    fn publish_pending(&self) {
+        let ring_id = self.pending.as_ref().map(|pending| pending.ring_id.clone());
+        let _ = self.ui.send_event(UiEvent::PendingRing(ring_id));
+    }
+
+    fn publish_own(&self) {
+        let _ = self.ui.send_event(UiEvent::OwnRing(self.own.clone()));
+    }
+}
+
+/// What a ring of `kind` says, naming the ringer when known.
+fn ring_text(kind: RingKind, sender: Option<&str>) -> String {
+    match (kind, sender) {
+        (RingKind::Doorbell, Some(name)) => format!("🔔 Ding Dong ! {} vous appelle !", name),
+        (RingKind::Doorbell, None) => "🔔 Ding Dong ! On vous appelle !".to_string(),
+        (RingKind::Delivery, Some(name)) => {
+            format!("📦 Livraison ! {} a un colis pour vous.", name)
+        }
+        (RingKind::Delivery, None) => "📦 Livraison ! Un colis vous attend.".to_string(),
+        (RingKind::Lunch, Some(name)) => format!("🍽️ À table ! {} vous attend pour manger.", name),
+        (RingKind::Lunch, None) => "🍽️ À table ! C'est l'heure de manger.".to_string(),
+        (RingKind::Meeting, Some(name)) => format!("📅 Réunion ! {} vous attend.", name),
+        (RingKind::Meeting, None) => "📅 Réunion ! Elle commence.".to_string(),
+        (RingKind::Emergency, Some(name)) => {
+            format!("🚨 URGENCE ! {} a besoin de vous tout de suite !", name)
+        }
+        (RingKind::Emergency, None) => {
+            "🚨 URGENCE ! On a besoin de vous tout de suite !".to_string()
+        }
+    }
+}
+
+/// Escapes `&`, `<` and `>`, which notification servers read as markup in the body.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Elsewhere the body is plain text and shown as is.
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn escape_markup(text: &str) -> String {
+    text.to_string()
+}
+
+/// Shows `notification`, turning a click on its answer action into [`UiEvent::Acknowledge`].
+///
+/// Returns the notification's ID, to replace it later.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn show(
+    notification: &Notification,
+    ring_id: Option<String>,
+    ui: &EventLoopProxy<UiEvent>,
+) -> Option<u32> {
+    let handle = match notification.show() {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Failed to show notification: {}", e);
+            return None;
+        }
+    };
+    let id = handle.id();
+    if let Some(ring_id) = ring_id {
+        let ui = ui.clone();
+        // Blocks until the notification goes away, so it gets its own thread.
+        std::thread::spawn(move || {
+            handle.wait_for_action(|action| {
+                if action == ACK_ACTION {
+                    let _ = ui.send_event(UiEvent::Acknowledge(ring_id));
+                }
+            })
+        });
+    }
+    Some(id)
+}
+
+/// Elsewhere notifications can't report actions nor be updated; the tray answers instead.
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn show(
+    notification: &Notification,
+    _ring_id: Option<String>,
+    _ui: &EventLoopProxy<UiEvent>,
+) -> Option<u32> {
+    if let Err(e) = notification.show() {
+        eprintln!("Failed to show notification: {}", e);
+    }
+    None
+}
+
+#[cfg(all(test, unix, not(target_os = "macos")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_markup_neutralises_tags_and_entities() {
+        assert_eq!(escape_markup("Alice"), "Alice");
+        assert_eq!(
+            escape_markup("<b>Bob</b> & <a href=\"x\">co</a>"),
+            "&lt;b&gt;Bob&lt;/b&gt; &amp; &lt;a href=\"x\"&gt;co&lt;/a&gt;"
+        );
+        assert_eq!(escape_markup("&lt;"), "&amp;lt;");
+    }
+}
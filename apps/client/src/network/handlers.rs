@@ -1,8 +1,12 @@
+use super::Frontend;
 use crate::audio::{play_random_sound, play_sound_by_hash};
-use crate::sync::save_file;
-use common::{FileTransfer, WsMessage};
-use uuid::Uuid;
+use crate::sync::{sync_with_manifest, write_chunk, ChunkOutcome};
+use common::transfer::decode_asset;
+use common::{Manifest, RingBell, Status, Urgency, WsMessage};
 
+/// Handles one frame from the server, queueing any answers in `replies`.
+///
+/// Returns `false` when the connection is gone.
 pub fn handle_incoming_message(
     msg: Option<
         Result<
@@ -10,19 +14,27 @@ pub fn handle_incoming_message(
             tokio_tungstenite::tungstenite::Error,
         >,
     >,
-    my_uuid: Uuid,
+    my_id: &str,
+    frontend: &mut Frontend,
+    replies: &mut Vec<WsMessage>,
 ) -> bool {
     use tokio_tungstenite::tungstenite::protocol::Message;
     match msg {
         Some(Ok(Message::Text(text))) => {
-            if let Ok(parsed) = serde_json::from_str::<WsMessage>(&text) {
-                dispatch_event(&parsed, my_uuid);
-            } else if text.trim() == "ring_bell" {
-                println!("Ring bell triggered (legacy)!");
-                let _ = play_random_sound();
+            match WsMessage::from_text(&text) {
+                Ok(parsed) => dispatch_event(&parsed, my_id, frontend, replies),
+                Err(e) => eprintln!("Ignoring invalid message from server: {}", e),
             }
             true
         }
+        Some(Ok(Message::Binary(frame))) => {
+            handle_asset_frame(&frame, frontend);
+            true
+        }
+        Some(Ok(Message::Close(Some(frame)))) => {
+            eprintln!("Server closed the connection: {}", frame.reason);
+            false
+        }
         Some(Ok(_)) => true,
         Some(Err(e)) => {
             eprintln!("Error receiving message: {}", e);
@@ -32,60 +44,100 @@ pub fn handle_incoming_message(
     }
 }
 
-fn dispatch_event(parsed: &WsMessage, my_uuid: Uuid) {
-    match parsed.event.as_str() {
-        "ring_bell" => handle_ring_bell(parsed, my_uuid),
-        "file_transfer" => handle_file_transfer(parsed),
+fn dispatch_event(
+    parsed: &WsMessage,
+    my_id: &str,
+    frontend: &mut Frontend,
+    replies: &mut Vec<WsMessage>,
+) {
+    match parsed {
+        WsMessage::RingBell(ring) => {
+            frontend.recent.push(ring);
+            handle_ring_bell(ring, my_id, frontend)
+        }
+        WsMessage::RingSent(sent) => frontend.alerts.sent(sent),
+        WsMessage::RingCancelled(cancelled) => frontend.alerts.cancelled(cancelled),
+        WsMessage::Acknowledged(ack) => frontend.alerts.acknowledged(ack, my_id),
+        WsMessage::History(history) => frontend.recent.replace(&history.rings),
+        WsMessage::Manifest(manifest) => handle_manifest(manifest, frontend, replies),
+        WsMessage::Presence(presence) => frontend.roster.replace(&presence.people),
+        WsMessage::Joined(person) | WsMessage::StatusChanged(person) => {
+            frontend.roster.update(person)
+        }
+        WsMessage::Left(person) => frontend.roster.remove(person),
+        WsMessage::Subscribed(subscription) => {
+            println!("Listening to rooms: {}", subscription.rooms.join(", "))
+        }
+        WsMessage::Error(error) => eprintln!("Server rejected our message: {}", error),
+        // Handshake and client-to-server messages have no business here.
         _ => {}
     }
 }
 
-fn handle_ring_bell(parsed: &WsMessage, my_uuid: Uuid) {
-    if should_ring(parsed, my_uuid) {
+fn handle_ring_bell(ring: &RingBell, my_id: &str, frontend: &mut Frontend) {
+    if should_ring(ring, my_id) {
         println!("Ring bell triggered!");
+        frontend.alerts.ring(ring);
+
+        if frontend.status == Status::Busy && ring.kind.urgency() < Urgency::Critical {
+            println!("Do not disturb: not playing a sound.");
+            return;
+        }
 
+        // Emergencies are heard even with the sound muted.
+        let urgent = ring.kind.urgency() == Urgency::Critical;
         let mut played_specific = false;
-        if let Some(data) = &parsed.data {
-            if let Ok(hash) = serde_json::from_value::<String>(data.clone()) {
-                println!("Server requested hash: {}", hash);
-                if let Err(e) = play_sound_by_hash(&hash) {
-                    eprintln!("Failed to play by hash: {}", e);
-                } else {
-                    played_specific = true;
-                }
+        if let Some(hash) = &ring.sound_hash {
+            println!("Server requested hash: {}", hash);
+            if let Err(e) = play_sound_by_hash(hash, urgent) {
+                eprintln!("Failed to play by hash: {}", e);
+            } else {
+                played_specific = true;
             }
         }
 
         if !played_specific {
             println!("No hash provided or failed. Playing random fallback.");
-            if let Err(e) = play_random_sound() {
+            if let Err(e) = play_random_sound(urgent) {
                 eprintln!("Failed to play sound: {}", e);
             }
         }
-
-        // Show Notification
-        use notify_rust::Notification;
-        let _ = Notification::new()
-            .summary("Sonnerie")
-            .body("🔔 Ding Dong ! On vous appelle !")
-            .appname("Sonnerie")
-            .show();
     }
 }
 
-fn handle_file_transfer(parsed: &WsMessage) {
-    if let Some(data) = &parsed.data {
-        if let Ok(transfer) = serde_json::from_value::<FileTransfer>(data.clone()) {
-            if let Err(e) = save_file(&transfer.filename, &transfer.content) {
-                eprintln!("Failed to save file {}: {}", transfer.filename, e);
-            }
+fn handle_manifest(manifest: &Manifest, frontend: &mut Frontend, replies: &mut Vec<WsMessage>) {
+    match sync_with_manifest(&manifest.assets, &mut frontend.downloads) {
+        Ok(fetches) if fetches.is_empty() => println!("Assets are up to date."),
+        Ok(fetches) => {
+            println!("Requesting {} assets from server.", fetches.len());
+            replies.push(WsMessage::fetch_assets(fetches));
         }
+        Err(e) => eprintln!("Failed to sync assets: {}", e),
     }
 }
 
-fn should_ring(parsed: &WsMessage, my_uuid: Uuid) -> bool {
-    match parsed.sender_id {
-        Some(ref id) => id != &my_uuid.to_string(),
-        None => true,
+fn handle_asset_frame(frame: &[u8], frontend: &mut Frontend) {
+    match decode_asset(frame) {
+        Ok((header, content)) => match write_chunk(&header, content, &mut frontend.downloads) {
+            Ok(ChunkOutcome::InProgress { received, total }) => {
+                // Only report every 10% to keep the log readable on large files.
+                if header.offset * 10 / total != received * 10 / total {
+                    println!(
+                        "Downloading {}: {}% ({}/{} bytes)",
+                        header.filename,
+                        received * 100 / total,
+                        received,
+                        total
+                    );
+                }
+            }
+            Ok(ChunkOutcome::Complete) => {}
+            Err(e) => eprintln!("Failed to save file {}: {}", header.filename, e),
+        },
+        Err(e) => eprintln!("Ignoring invalid asset frame: {}", e),
     }
 }
+
+fn should_ring(ring: &RingBell, my_id: &str) -> bool {
+    ring.sender_id.as_deref() != Some(my_id)
+}
@@ -0,0 +1,85 @@
+use super::{WsReceiver, WsSender};
+use crate::audio::SUPPORTED_FORMATS;
+use crate::identity::Identity;
+use common::{Hello, Status, Welcome, WsMessage, PROTOCOL_VERSION};
+use futures_util::{SinkExt, StreamExt};
+use std::fmt;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+const WELCOME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Optional features this client can use if the server agrees.
+const CLIENT_FEATURES: &[&str] = &[];
+
+pub enum HandshakeError {
+    /// The server refused us; retrying quickly won't help.
+    Rejected(String),
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Rejected(reason) => {
+                write!(f, "server rejected this client: {}", reason)
+            }
+            HandshakeError::Failed(e) => write!(f, "handshake failed: {}", e),
+        }
+    }
+}
+
+impl From<anyhow::Error> for HandshakeError {
+    fn from(e: anyhow::Error) -> Self {
+        HandshakeError::Failed(e)
+    }
+}
+
+/// Sends our `hello` and waits for the server's verdict.
+pub async fn perform(
+    identity: &Identity,
+    rooms: &[String],
+    status: Status,
+    write: &mut WsSender,
+    read: &mut WsReceiver,
+) -> Result<Welcome, HandshakeError> {
+    let hello = WsMessage::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        client_id: identity.client_id.clone(),
+        client_secret: identity.client_secret.clone(),
+        display_name: Some(identity.display_name.clone()),
+        rooms: rooms.to_vec(),
+        status,
+        audio_formats: SUPPORTED_FORMATS.iter().map(|f| f.to_string()).collect(),
+        features: CLIENT_FEATURES.iter().map(|f| f.to_string()).collect(),
+    });
+    write
+        .send(Message::Text(hello.to_text()))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    loop {
+        let msg = tokio::time::timeout(WELCOME_TIMEOUT, read.next())
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out waiting for welcome"))?;
+
+        match msg {
+            Some(Ok(Message::Text(text))) => {
+                return match WsMessage::from_text(&text) {
+                    Ok(WsMessage::Welcome(welcome)) => Ok(welcome),
+                    Ok(WsMessage::Rejected(rejected)) => {
+                        Err(HandshakeError::Rejected(rejected.reason))
+                    }
+                    Ok(other) => {
+                        Err(anyhow::anyhow!("unexpected reply to hello: {:?}", other).into())
+                    }
+                    Err(e) => Err(anyhow::anyhow!(e).into()),
+                };
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(anyhow::anyhow!(e).into()),
+            None => return Err(anyhow::anyhow!("connection closed during handshake").into()),
+        }
+    }
+}
@@ -1,70 +1,178 @@
+mod alerts;
 pub mod handlers;
+mod handshake;
+mod presence;
+mod recent;
 
-use crate::sync::get_local_hashes;
-use anyhow::{Context, Result};
-use common::WsMessage;
+use crate::config::{NotificationConfig, ServerConfig};
+use crate::identity::Identity;
+use crate::sync::DownloadNames;
+use crate::tray::UiEvent;
+use common::{Status, WsMessage};
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use std::time::Duration;
+use tao::event_loop::EventLoopProxy;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+use tokio_tungstenite::tungstenite::Error as WsError;
 use tokio_tungstenite::MaybeTlsStream;
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use url::Url;
-use uuid::Uuid;
 
 // Use handlers
+use alerts::Alerts;
 use handlers::handle_incoming_message;
+use handshake::HandshakeError;
+use presence::Roster;
+use recent::RecentRings;
 
 type WsSender = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
 type WsReceiver = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
 
-// const SERVER_URL: &str = "ws://51.254.128.175:3000/ws";
+/// What the user sees and hears of the connection: tray contents, notifications and sounds.
+pub struct Frontend {
+    roster: Roster,
+    recent: RecentRings,
+    alerts: Alerts,
+    /// Last status we asked for, announced again in the hello after reconnecting. Busy means
+    /// do not disturb.
+    status: Status,
+    /// Names of the sounds being downloaded, kept across reconnections so resumed downloads
+    /// are still indexed under all of them.
+    downloads: DownloadNames,
+}
 
-pub async fn run_ws_client(my_uuid: Uuid, mut rx_input: mpsc::Receiver<WsMessage>) {
-    let server_url = std::env::var("SERVER_URL").expect("SERVER_URL must be set");
-    let url = Url::parse(&server_url).expect("Invalid URL");
+/// Stays connected to one of `server.urls` for as long as the client runs.
+///
+/// Failed attempts move on to the next URL and wait longer each time, up to the configured
+/// maximum; a session that got through the handshake starts over from the shortest delay.
+pub async fn run_ws_client(
+    server: ServerConfig,
+    notifications: NotificationConfig,
+    mut identity: Identity,
+    ui: EventLoopProxy<UiEvent>,
+    mut rx_input: mpsc::Receiver<WsMessage>,
+) {
+    let ServerConfig {
+        urls,
+        token,
+        rooms,
+        reconnect,
+    } = server;
+    let mut frontend = Frontend {
+        roster: Roster::new(ui.clone()),
+        recent: RecentRings::new(ui.clone()),
+        alerts: Alerts::new(ui, notifications),
+        status: Status::default(),
+        downloads: DownloadNames::new(),
+    };
 
+    let mut server_index = 0;
+    let mut backoff = reconnect.delay();
     loop {
+        let url = &urls[server_index];
+        let mut connected = false;
+        // Being refused usually means we need an upgrade, so don't hammer the server.
+        let mut rejected = false;
         println!("Connecting to {}...", url);
-        match connect_async(url.clone()).await {
+        match connect_async(build_request(url, token.as_deref())).await {
             Ok((ws_stream, _)) => {
-                println!("Connected to WebSocket server with ID: {}", my_uuid);
-                let (mut write, read) = ws_stream.split();
+                let (mut write, mut read) = ws_stream.split();
 
-                if let Err(e) = send_sync_hashes(&mut write).await {
-                    eprintln!("Failed to send sync hashes: {}", e);
-                }
+                match handshake::perform(&identity, &rooms, frontend.status, &mut write, &mut read)
+                    .await
+                {
+                    Ok(welcome) => {
+                        connected = true;
+                        println!(
+                            "Connected to WebSocket server {} with ID: {}",
+                            welcome.server_version, welcome.client_id
+                        );
+                        warn_missing_rooms(&rooms, &welcome.rooms);
+                        if let Err(e) = identity.adopt(&welcome.client_id, &welcome.client_secret) {
+                            eprintln!("Failed to save identity: {:#}", e);
+                        }
 
-                run_interaction_loop(my_uuid, write, read, &mut rx_input).await;
+                        run_interaction_loop(
+                            &welcome.client_id,
+                            write,
+                            read,
+                            &mut frontend,
+                            &mut rx_input,
+                        )
+                        .await;
+                        frontend.roster.clear();
+                        frontend.alerts.clear();
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        if let HandshakeError::Rejected(_) = e {
+                            rejected = true;
+                        }
+                    }
+                }
+            }
+            Err(WsError::Http(response)) if response.status() == StatusCode::UNAUTHORIZED => {
+                eprintln!("Server refused our token, check the configured token.");
+                rejected = true;
             }
             Err(e) => {
                 eprintln!("Failed to connect: {}", e);
             }
         }
-        println!("Disconnected, retrying in 5 seconds...");
-        sleep(Duration::from_secs(5)).await;
+
+        let retry_delay = if connected {
+            backoff = reconnect.delay();
+            backoff
+        } else {
+            server_index = (server_index + 1) % urls.len();
+            let delay = if rejected {
+                reconnect.rejected_delay()
+            } else {
+                backoff
+            };
+            backoff = (backoff * 2).min(reconnect.max_delay());
+            delay
+        };
+        println!(
+            "Disconnected, retrying in {} seconds...",
+            retry_delay.as_secs()
+        );
+        sleep(retry_delay).await;
     }
 }
 
-async fn send_sync_hashes(write: &mut WsSender) -> Result<()> {
-    let hashes = get_local_hashes().context("Failed to get local hashes")?;
-    let msg = WsMessage::sync_hashes(hashes);
-    let text = serde_json::to_string(&msg)?;
-    write
-        .send(Message::Text(text.into()))
-        .await
-        .map_err(|e| anyhow::anyhow!(e))?;
-    println!("Sent audio file hashes for synchronization.");
-    Ok(())
+/// Tells the user about requested rooms the server doesn't have.
+fn warn_missing_rooms(requested: &[String], granted: &[String]) {
+    println!("Listening to rooms: {}", granted.join(", "));
+    for room in requested.iter().filter(|room| !granted.contains(room)) {
+        eprintln!("Server has no room named {}, ignoring it.", room);
+    }
+}
+
+/// Upgrade request for `url`, carrying our token if we have one.
+fn build_request(url: &Url, token: Option<&str>) -> Request {
+    let mut request = url.as_str().into_client_request().expect("Invalid URL");
+    if let Some(token) = token {
+        let value = format!("Bearer {}", token)
+            .parse()
+            .expect("the token was checked when loading the configuration");
+        request.headers_mut().insert(header::AUTHORIZATION, value);
+    }
+    request
 }
 
 async fn run_interaction_loop(
-    my_uuid: Uuid,
+    my_id: &str,
     mut write: WsSender,
     mut read: WsReceiver,
+    frontend: &mut Frontend,
     rx_input: &mut mpsc::Receiver<WsMessage>,
 ) {
     let mut ping_interval = tokio::time::interval(Duration::from_secs(30));
@@ -84,11 +192,20 @@ async fn run_interaction_loop(
                 }
             }
             some_msg = read.next() => {
-                if !handle_incoming_message(some_msg, my_uuid) {
+                let mut replies = Vec::new();
+                if !handle_incoming_message(some_msg, my_id, frontend, &mut replies) {
                     break;
                 }
+                for reply in replies {
+                    if !send_message(&mut write, reply).await {
+                        return;
+                    }
+                }
             }
             Some(msg) = rx_input.recv() => {
+                if let WsMessage::SetStatus(request) = &msg {
+                    frontend.status = request.status;
+                }
                 if !send_message(&mut write, msg).await {
                     break;
                 }
@@ -98,8 +215,7 @@ async fn run_interaction_loop(
 }
 
 async fn send_message(write: &mut WsSender, msg: WsMessage) -> bool {
-    let text = serde_json::to_string(&msg).unwrap();
-    if let Err(e) = write.send(Message::Text(text.into())).await {
+    if let Err(e) = write.send(Message::Text(msg.to_text())).await {
         eprintln!("Failed to send message: {}", e);
         return false;
     }
@@ -0,0 +1,51 @@
+use crate::tray::UiEvent;
+use common::PersonPresence;
+use std::collections::HashMap;
+use tao::event_loop::EventLoopProxy;
+
+/// Who is connected to the server, mirrored into the tray.
+pub struct Roster {
+    people: HashMap<String, PersonPresence>,
+    ui: EventLoopProxy<UiEvent>,
+}
+
+impl Roster {
+    pub fn new(ui: EventLoopProxy<UiEvent>) -> Self {
+        Self {
+            people: HashMap::new(),
+            ui,
+        }
+    }
+
+    pub fn replace(&mut self, people: &[PersonPresence]) {
+        self.people = people
+            .iter()
+            .map(|person| (person.client_id.clone(), person.clone()))
+            .collect();
+        self.publish();
+    }
+
+    /// Someone joined or changed status.
+    pub fn update(&mut self, person: &PersonPresence) {
+        self.people.insert(person.client_id.clone(), person.clone());
+        self.publish();
+    }
+
+    pub fn remove(&mut self, person: &PersonPresence) {
+        self.people.remove(&person.client_id);
+        self.publish();
+    }
+
+    /// Forgets everyone, e.g. once the connection is lost.
+    pub fn clear(&mut self) {
+        self.people.clear();
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let mut people: Vec<PersonPresence> = self.people.values().cloned().collect();
+        people.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        // Only fails once the event loop is gone, i.e. while quitting.
+        let _ = self.ui.send_event(UiEvent::Presence(people));
+    }
+}
@@ -0,0 +1,52 @@
+use crate::tray::UiEvent;
+use common::{RingBell, RingSummary};
+use std::collections::VecDeque;
+use tao::event_loop::EventLoopProxy;
+
+/// How many rings the tray lists.
+const RECENT_RINGS: usize = 10;
+
+/// Latest rings heard, mirrored into the tray, most recent first.
+pub struct RecentRings {
+    rings: VecDeque<RingSummary>,
+    ui: EventLoopProxy<UiEvent>,
+}
+
+impl RecentRings {
+    pub fn new(ui: EventLoopProxy<UiEvent>) -> Self {
+        Self {
+            rings: VecDeque::new(),
+            ui,
+        }
+    }
+
+    /// Takes the server's history, sent after each (re)connection.
+    pub fn replace(&mut self, rings: &[RingSummary]) {
+        self.rings = rings.iter().take(RECENT_RINGS).cloned().collect();
+        self.publish();
+    }
+
+    /// Lists a ring we just heard; the server's re-rings of it aren't listed again.
+    ///
+    /// Like the server's history, only lists rings for a whole room, so the tray shows the same
+    /// rings before and after reconnecting.
+    pub fn push(&mut self, ring: &RingBell) {
+        if ring.attempt > 0 || ring.target.is_some() {
+            return;
+        }
+        self.rings.push_front(RingSummary {
+            room: ring.room.clone().unwrap_or_default(),
+            sender_name: ring.sender_name.clone().unwrap_or_default(),
+            at: chrono::Utc::now().timestamp(),
+            message: ring.message.clone(),
+        });
+        self.rings.truncate(RECENT_RINGS);
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let _ = self
+            .ui
+            .send_event(UiEvent::RecentRings(self.rings.iter().cloned().collect()));
+    }
+}
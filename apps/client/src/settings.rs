@@ -0,0 +1,85 @@
+use anyhow::{bail, Context, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const AUDIO_SETTINGS_FILE: &str = "audio.json";
+
+/// Sound output choices made from the tray, kept across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AudioSettings {
+    /// Output device name; the system default if unset or unplugged.
+    pub device: Option<String>,
+    /// Percent of the sounds' own volume.
+    pub volume: u8,
+    /// Silences every ring but emergencies.
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            device: None,
+            volume: 100,
+            muted: false,
+        }
+    }
+}
+
+impl AudioSettings {
+    /// Loads the stored settings; none before anything was saved.
+    pub fn load() -> Result<Option<Self>> {
+        let path = settings_path()?;
+        match fs::read(&path) {
+            Ok(bytes) => Self::parse(&bytes).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read audio settings file"),
+        }
+    }
+
+    /// Reads a settings file, refusing values the tray could not have saved.
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let settings: Self =
+            serde_json::from_slice(bytes).context("Corrupt audio settings file")?;
+        if settings.volume > 100 {
+            bail!("Audio settings volume is {}, at most 100", settings.volume);
+        }
+        Ok(settings)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = settings_path()?;
+        fs::create_dir_all(path.parent().unwrap()).context("Failed to create config directory")?;
+        fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .context("Failed to save audio settings file")
+    }
+
+    pub fn gain(&self) -> f32 {
+        f32::from(self.volume) / 100.0
+    }
+}
+
+fn settings_path() -> Result<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "sonnerie")
+        .context("Could not determine the user config directory")?;
+    Ok(dirs.config_dir().join(AUDIO_SETTINGS_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_volume_is_at_most_100() {
+        let settings = AudioSettings::parse(br#"{"volume": 40, "muted": true}"#).unwrap();
+        assert_eq!(settings.volume, 40);
+        assert!(settings.muted);
+        assert_eq!(settings.device, None);
+
+        assert!(AudioSettings::parse(br#"{"volume": 100}"#).is_ok());
+        assert!(AudioSettings::parse(br#"{"volume": 150}"#).is_err());
+        assert!(AudioSettings::parse(br#"{"volume": 300}"#).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Content-addressed asset store.
+//!
+//! Layout under the assets directory, `assets` unless configured otherwise:
+//!
+//! ```text
+//! assets/
+//!   index.json        hash -> display name and metadata
+//!   objects/<hash>    asset content, named by its SHA-256
+//!   .partial/         interrupted downloads, as <hash>.part
+//!   .stale/           assets the server stopped publishing
+//! ```
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const ASSETS_DIR: &str = "assets";
+const OBJECTS_DIR: &str = "objects";
+const INDEX_FILE: &str = "index.json";
+const PARTIAL_DIR: &str = ".partial";
+const STALE_DIR: &str = ".stale";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "StoredEntry")]
+pub struct IndexEntry {
+    /// Names the server publishes this content under, sorted; the same sound may be published
+    /// twice.
+    pub names: Vec<String>,
+    pub size: u64,
+}
+
+impl IndexEntry {
+    /// Name to show for this asset.
+    pub fn name(&self) -> &str {
+        self.names.first().map_or("", String::as_str)
+    }
+}
+
+/// An index entry as found on disk, where older indexes have a single `name`.
+#[derive(Deserialize)]
+struct StoredEntry {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    name: Option<String>,
+    size: u64,
+}
+
+impl From<StoredEntry> for IndexEntry {
+    fn from(stored: StoredEntry) -> Self {
+        let mut names = stored.names;
+        names.extend(stored.name);
+        names.sort();
+        names.dedup();
+        Self {
+            names,
+            size: stored.size,
+        }
+    }
+}
+
+/// Every stored asset, keyed by content hash.
+pub type Index = BTreeMap<String, IndexEntry>;
+
+/// Assets directory set at startup.
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Stores assets under `dir` instead of `assets`; only the first call counts.
+pub fn set_dir(dir: PathBuf) {
+    let _ = DIR.set(dir);
+}
+
+fn assets_dir() -> &'static Path {
+    DIR.get().map_or(Path::new(ASSETS_DIR), PathBuf::as_path)
+}
+
+/// Where the content for `hash` lives, whether or not it is present.
+pub fn object_path(hash: &str) -> PathBuf {
+    assets_dir().join(OBJECTS_DIR).join(hash)
+}
+
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Loads the index, dropping entries whose object has gone missing and
+/// importing loose audio files left by the old name-based layout.
+pub fn load_index() -> Result<Index> {
+    let assets = assets_dir();
+    fs::create_dir_all(assets.join(OBJECTS_DIR)).context("Failed to create assets directory")?;
+
+    let index_path = assets.join(INDEX_FILE);
+    let mut index: Index = if index_path.exists() {
+        serde_json::from_slice(&fs::read(&index_path)?).context("Corrupt asset index")?
+    } else {
+        Index::new()
+    };
+
+    let before = index.clone();
+    index.retain(|hash, _| object_path(hash).is_file());
+    import_loose_files(&mut index)?;
+    if index != before {
+        save_index(&index)?;
+    }
+    Ok(index)
+}
+
+pub fn save_index(index: &Index) -> Result<()> {
+    let assets = assets_dir();
+    // Write then rename so a crash never leaves a half-written index.
+    let tmp = assets.join(format!("{}.tmp", INDEX_FILE));
+    fs::write(&tmp, serde_json::to_vec_pretty(index)?)?;
+    fs::rename(tmp, assets.join(INDEX_FILE))?;
+    Ok(())
+}
+
+fn import_loose_files(index: &mut Index) -> Result<()> {
+    for entry in fs::read_dir(assets_dir())? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let Some(filename) = path.file_name().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if !(filename.ends_with(".mp3") || filename.ends_with(".wav")) {
+            continue;
+        }
+
+        let hash = hash_file(&path)?;
+        match index.entry(hash) {
+            Entry::Occupied(_) => fs::remove_file(&path)?,
+            Entry::Vacant(slot) => {
+                let size = fs::metadata(&path)?.len();
+                fs::rename(&path, object_path(slot.key()))?;
+                slot.insert(IndexEntry {
+                    names: vec![filename.to_string()],
+                    size,
+                });
+            }
+        }
+        println!("Imported legacy asset: {}", filename);
+    }
+    Ok(())
+}
+
+/// Moves a verified download into the store and records it in the index under `names`.
+pub fn commit_partial(index: &mut Index, hash: &str, mut names: Vec<String>) -> Result<()> {
+    let object = object_path(hash);
+    fs::rename(partial_path(hash)?, &object)?;
+    names.sort();
+    names.dedup();
+    index.insert(
+        hash.to_string(),
+        IndexEntry {
+            names,
+            size: fs::metadata(&object)?.len(),
+        },
+    );
+    save_index(index)
+}
+
+/// Takes an asset out of the index and moves its content aside instead of deleting it.
+pub fn quarantine(index: &mut Index, hash: &str) -> Result<()> {
+    let stale_dir = assets_dir().join(STALE_DIR);
+    fs::create_dir_all(&stale_dir).context("Failed to create stale assets directory")?;
+    fs::rename(object_path(hash), stale_dir.join(hash))?;
+    index.remove(hash);
+    Ok(())
+}
+
+pub fn partial_path(hash: &str) -> Result<PathBuf> {
+    let dir = assets_dir().join(PARTIAL_DIR);
+    fs::create_dir_all(&dir).context("Failed to create partial downloads directory")?;
+    Ok(dir.join(format!("{}.part", hash)))
+}
+
+/// Lists interrupted downloads as file hash to bytes already on disk.
+pub fn partial_sizes() -> Result<HashMap<String, u64>> {
+    let mut partials = HashMap::new();
+    let dir = assets_dir().join(PARTIAL_DIR);
+    if !dir.exists() {
+        return Ok(partials);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(hash) = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .and_then(|name| name.strip_suffix(".part"))
+        {
+            partials.insert(hash.to_string(), fs::metadata(&path)?.len());
+        }
+    }
+    Ok(partials)
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 1024];
+
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -1,66 +1,265 @@
+use crate::store::{self, Index};
 use anyhow::{Context, Result};
-use base64::{engine::general_purpose, Engine as _};
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use common::transfer::AssetHeader;
+use common::{AssetEntry, AssetFetch};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::Write;
 
-const ASSETS_DIR: &str = "assets";
+/// Every name the manifest gives a download's content, by hash, so the completed download is
+/// indexed under all of them rather than only the one it was fetched as.
+pub type DownloadNames = BTreeMap<String, Vec<String>>;
 
-pub fn get_local_hashes() -> Result<HashMap<String, String>> {
-    let mut hashes = HashMap::new();
-    let path = Path::new(ASSETS_DIR);
+pub enum ChunkOutcome {
+    InProgress { received: u64, total: u64 },
+    Complete,
+}
 
-    if !path.exists() {
-        fs::create_dir_all(path).context("Failed to create assets directory")?;
-    }
+/// What it takes to bring the local store in line with the server manifest.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Content we don't have yet, once per hash.
+    pub downloads: Vec<AssetEntry>,
+    /// Stored assets the server now publishes under other names, as `(hash, sorted names)`.
+    pub renames: Vec<(String, Vec<String>)>,
+    /// Stored hashes the server no longer publishes.
+    pub stale: Vec<String>,
+    /// Every published name of each download, by hash.
+    pub download_names: DownloadNames,
+}
 
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let path = entry.path();
+/// Compares the manifest with the store. Content is keyed by hash, so a sound published under
+/// several names is fetched once and indexed under all of them.
+pub fn plan_sync(manifest: &[AssetEntry], index: &Index) -> SyncPlan {
+    let mut published: BTreeMap<&str, (&AssetEntry, BTreeSet<&str>)> = BTreeMap::new();
+    for asset in manifest {
+        published
+            .entry(asset.hash.as_str())
+            .or_insert_with(|| (asset, BTreeSet::new()))
+            .1
+            .insert(asset.name.as_str());
+    }
 
-        if path.is_file() {
-            if let Some(filename) = path.file_name().and_then(|s| s.to_str()) {
-                // Determine if audio file
-                if filename.ends_with(".mp3") || filename.ends_with(".wav") {
-                    let hash = calculate_hash(&path)?;
-                    hashes.insert(filename.to_string(), hash);
-                }
+    let mut plan = SyncPlan::default();
+    for (hash, (asset, names)) in &published {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        match index.get(*hash) {
+            Some(entry) if entry.names == names => {}
+            Some(_) => plan.renames.push((hash.to_string(), names)),
+            None => {
+                plan.downloads.push((*asset).clone());
+                plan.download_names.insert(hash.to_string(), names);
             }
         }
     }
-    Ok(hashes)
+
+    plan.stale = index
+        .keys()
+        .filter(|hash| !published.contains_key(hash.as_str()))
+        .cloned()
+        .collect();
+    plan
+}
+
+/// Applies renames and quarantines stale assets; downloads are left to the caller.
+pub fn apply_local_changes(plan: &SyncPlan, index: &mut Index) -> Result<()> {
+    for hash in &plan.stale {
+        let name = index.get(hash).map(|entry| entry.name().to_string());
+        store::quarantine(index, hash)?;
+        println!("Quarantined stale asset: {}", name.unwrap_or_default());
+    }
+
+    // Content is keyed by hash, so a rename is only an index update.
+    for (hash, names) in &plan.renames {
+        if let Some(entry) = index.get_mut(hash) {
+            println!(
+                "Renamed asset: {} -> {}",
+                entry.names.join(", "),
+                names.join(", ")
+            );
+            entry.names = names.clone();
+        }
+    }
+
+    store::save_index(index)
 }
 
-fn calculate_hash(path: &Path) -> Result<String> {
-    let mut file = fs::File::open(path)?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0; 1024];
+/// Reconciles the store with a manifest and returns what to fetch,
+/// resuming interrupted downloads where possible.
+///
+/// The names of each download are added to `downloads`, leaving those of transfers still under
+/// way from an earlier manifest in place.
+pub fn sync_with_manifest(
+    manifest: &[AssetEntry],
+    downloads: &mut DownloadNames,
+) -> Result<Vec<AssetFetch>> {
+    let mut index = store::load_index().context("Failed to load asset index")?;
+    let plan = plan_sync(manifest, &index);
+    apply_local_changes(&plan, &mut index)?;
+    downloads.extend(plan.download_names);
+
+    let partials = store::partial_sizes().context("Failed to list partial downloads")?;
+    Ok(plan
+        .downloads
+        .into_iter()
+        .map(|asset| AssetFetch {
+            offset: partials.get(&asset.hash).copied().unwrap_or(0),
+            name: asset.name,
+            hash: asset.hash,
+        })
+        .collect())
+}
 
-    loop {
-        let count = file.read(&mut buffer)?;
-        if count == 0 {
-            break;
+/// Appends a received chunk to its partial file, and moves the file into the
+/// store once the last chunk arrived and the whole content matches its hash.
+///
+/// A finished download is indexed under its names from `downloads`, then forgotten there.
+pub fn write_chunk(
+    header: &AssetHeader,
+    content: &[u8],
+    downloads: &mut DownloadNames,
+) -> Result<ChunkOutcome> {
+    // The hash names files on disk, so it must not smuggle a path in.
+    if !store::is_valid_hash(&header.hash) {
+        return Err(anyhow::anyhow!("Invalid hash"));
+    }
+
+    let partial_path = store::partial_path(&header.hash)?;
+    let mut file = if header.offset == 0 {
+        fs::File::create(&partial_path)?
+    } else {
+        let file = fs::OpenOptions::new().append(true).open(&partial_path)?;
+        let on_disk = file.metadata()?.len();
+        if on_disk != header.offset {
+            return Err(anyhow::anyhow!(
+                "Chunk at offset {} does not follow the {} bytes on disk",
+                header.offset,
+                on_disk
+            ));
         }
-        hasher.update(&buffer[..count]);
+        file
+    };
+    file.write_all(content)?;
+
+    if !header.is_last() {
+        return Ok(ChunkOutcome::InProgress {
+            received: header.offset + header.length,
+            total: header.total,
+        });
+    }
+
+    let names = downloads
+        .remove(&header.hash)
+        .unwrap_or_else(|| vec![header.filename.clone()]);
+    let actual_hash = store::hash_file(&partial_path)?;
+    if actual_hash != header.hash {
+        fs::remove_file(&partial_path)?;
+        return Err(anyhow::anyhow!(
+            "Hash mismatch (expected {}, got {})",
+            header.hash,
+            actual_hash
+        ));
     }
 
-    Ok(hex::encode(hasher.finalize()))
+    let mut index = store::load_index().context("Failed to load asset index")?;
+    store::commit_partial(&mut index, &header.hash, names)?;
+    println!("Downloaded asset: {}", header.filename);
+    Ok(ChunkOutcome::Complete)
 }
 
-pub fn save_file(filename: &str, content_base64: &str) -> Result<()> {
-    // Security check: simple filename only
-    if filename.contains('/') || filename.contains('\\') || filename.contains("..") {
-        return Err(anyhow::anyhow!("Invalid filename"));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::IndexEntry;
+
+    fn asset(name: &str, hash: &str) -> AssetEntry {
+        AssetEntry {
+            name: name.to_string(),
+            hash: hash.to_string(),
+            size: 3,
+        }
+    }
+
+    fn index(entries: &[(&str, &[&str])]) -> Index {
+        entries
+            .iter()
+            .map(|(hash, names)| {
+                (
+                    hash.to_string(),
+                    IndexEntry {
+                        names: names.iter().map(|name| name.to_string()).collect(),
+                        size: 3,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn up_to_date_store_needs_nothing() {
+        let manifest = [asset("cloche.mp3", "aa"), asset("gong.mp3", "bb")];
+        let index = index(&[("aa", &["cloche.mp3"]), ("bb", &["gong.mp3"])]);
+        assert_eq!(plan_sync(&manifest, &index), SyncPlan::default());
+    }
+
+    #[test]
+    fn missing_content_is_downloaded() {
+        let manifest = [asset("cloche.mp3", "aa")];
+        let plan = plan_sync(&manifest, &Index::new());
+        assert_eq!(plan.downloads, manifest);
+        assert_eq!(plan.download_names["aa"], ["cloche.mp3"]);
+        assert!(plan.renames.is_empty());
+        assert!(plan.stale.is_empty());
     }
 
-    let decoded = general_purpose::STANDARD.decode(content_base64)?;
-    let path = Path::new(ASSETS_DIR).join(filename);
+    #[test]
+    fn renamed_asset_is_renamed_not_downloaded() {
+        let manifest = [asset("sonnette.mp3", "aa")];
+        let plan = plan_sync(&manifest, &index(&[("aa", &["cloche.mp3"])]));
+        assert!(plan.downloads.is_empty());
+        assert_eq!(
+            plan.renames,
+            [("aa".to_string(), vec!["sonnette.mp3".to_string()])]
+        );
+    }
+
+    #[test]
+    fn duplicate_hash_is_downloaded_once_under_every_name() {
+        let manifest = [asset("b.mp3", "aa"), asset("a.mp3", "aa")];
+        let plan = plan_sync(&manifest, &Index::new());
+        assert_eq!(plan.downloads.len(), 1);
+        assert_eq!(plan.download_names["aa"], ["a.mp3", "b.mp3"]);
+    }
 
-    let mut file = fs::File::create(&path)?;
-    file.write_all(&decoded)?;
+    #[test]
+    fn duplicate_hash_converges() {
+        let manifest = [asset("b.mp3", "aa"), asset("a.mp3", "aa")];
+        let mut index = index(&[("aa", &["a.mp3"])]);
+        let plan = plan_sync(&manifest, &index);
+        assert_eq!(plan.renames.len(), 1);
 
-    println!("Downloaded asset: {}", filename);
-    Ok(())
+        for (hash, names) in plan.renames {
+            index.get_mut(&hash).unwrap().names = names;
+        }
+        assert_eq!(plan_sync(&manifest, &index), SyncPlan::default());
+    }
+
+    #[test]
+    fn unpublished_content_is_stale() {
+        let manifest = [asset("cloche.mp3", "aa")];
+        let plan = plan_sync(
+            &manifest,
+            &index(&[("aa", &["cloche.mp3"]), ("bb", &["gong.mp3"])]),
+        );
+        assert_eq!(plan.stale, ["bb"]);
+        assert!(plan.downloads.is_empty());
+        assert!(plan.renames.is_empty());
+    }
+
+    #[test]
+    fn legacy_index_entries_load() {
+        let index: Index =
+            serde_json::from_str(r#"{"aa": {"name": "cloche.mp3", "size": 3}}"#).unwrap();
+        assert_eq!(index["aa"].names, ["cloche.mp3"]);
+    }
 }
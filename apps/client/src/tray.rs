@@ -0,0 +1,332 @@
+use crate::input::HotkeyAction;
+use crate::settings::AudioSettings;
+use chrono::{Local, TimeZone};
+use common::{PersonPresence, RingSummary, Status};
+use std::iter;
+use tray_icon::{
+    menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu},
+    TrayIcon, TrayIconBuilder,
+};
+
+/// Updates the network and input threads send to the tray, which lives on the main thread.
+#[derive(Debug)]
+pub enum UiEvent {
+    /// Everyone currently connected, us included; empty while disconnected.
+    Presence(Vec<PersonPresence>),
+    /// Latest rings, most recent first.
+    RecentRings(Vec<RingSummary>),
+    /// Ring we could answer, if any.
+    PendingRing(Option<String>),
+    /// The user answered this ring from its notification.
+    Acknowledge(String),
+    /// Our own ring that could still be cancelled, if any.
+    OwnRing(Option<String>),
+    /// The user pressed a global hotkey.
+    Hotkey(HotkeyAction),
+    /// The audio output was opened again, maybe on another device than before.
+    AudioOutputChanged,
+}
+
+/// What the user asked for through the tray menu.
+pub enum TrayAction {
+    Acknowledge,
+    CancelRing,
+    /// Ring with one of the preset messages.
+    Ring(String),
+    SetStatus(Status),
+    SetMuted(bool),
+    /// Percent of the sounds' own volume.
+    SetVolume(u8),
+    /// Output device by name; the system default if unset.
+    SetDevice(Option<String>),
+    /// List the output devices again, e.g. after plugging one in.
+    RefreshDevices,
+    Quit,
+}
+
+const STATUSES: [Status; 3] = [Status::Available, Status::Busy, Status::Away];
+
+/// Volume steps offered by the tray, in percent.
+const VOLUMES: [u8; 4] = [25, 50, 75, 100];
+
+pub struct Tray {
+    _icon: TrayIcon,
+    statuses: Vec<(Status, CheckMenuItem)>,
+    online: Submenu,
+    recent: Submenu,
+    ack: MenuItem,
+    cancel: MenuItem,
+    messages: Vec<(String, MenuItem)>,
+    mute: CheckMenuItem,
+    volumes: Vec<(u8, CheckMenuItem)>,
+    device_menu: Submenu,
+    /// The system default output first, then each device by name.
+    devices: Vec<(Option<String>, CheckMenuItem)>,
+    refresh_devices: MenuItem,
+    quit: MenuItem,
+}
+
+impl Tray {
+    /// `messages` are offered in a "Sonner avec un message" submenu, left out if there are none.
+    ///
+    /// `devices` are the audio outputs to choose from besides the system default one.
+    pub fn new(
+        status: Status,
+        messages: &[String],
+        audio: &AudioSettings,
+        devices: &[String],
+    ) -> Self {
+        let menu = Menu::new();
+
+        let status_menu = Submenu::new("Statut", true);
+        let statuses: Vec<(Status, CheckMenuItem)> = STATUSES
+            .iter()
+            .map(|&s| {
+                (
+                    s,
+                    CheckMenuItem::new(status_label(s), true, s == status, None),
+                )
+            })
+            .collect();
+        for (_, item) in &statuses {
+            status_menu.append(item).unwrap();
+        }
+
+        let online = Submenu::new("En ligne", true);
+        let recent = Submenu::new("Dernières sonneries", true);
+        let ack = MenuItem::new("J'arrive", false, None);
+        let cancel = MenuItem::new("Annuler ma sonnerie", false, None);
+        let messages: Vec<(String, MenuItem)> = messages
+            .iter()
+            .map(|message| (message.clone(), MenuItem::new(message, true, None)))
+            .collect();
+
+        let sound_menu = Submenu::new("Son", true);
+        let mute = CheckMenuItem::new("Muet", true, audio.muted, None);
+        let volume_menu = Submenu::new("Volume", true);
+        let volumes: Vec<(u8, CheckMenuItem)> = VOLUMES
+            .iter()
+            .map(|&v| {
+                (
+                    v,
+                    CheckMenuItem::new(format!("{} %", v), true, v == audio.volume, None),
+                )
+            })
+            .collect();
+        for (_, item) in &volumes {
+            volume_menu.append(item).unwrap();
+        }
+        let device_menu = Submenu::new("Sortie", true);
+        sound_menu.append(&mute).unwrap();
+        sound_menu.append(&volume_menu).unwrap();
+        sound_menu.append(&device_menu).unwrap();
+
+        let quit = MenuItem::new("Quitter", true, None);
+        menu.append(&ack).unwrap();
+        menu.append(&cancel).unwrap();
+        if !messages.is_empty() {
+            let message_menu = Submenu::new("Sonner avec un message", true);
+            for (_, item) in &messages {
+                message_menu.append(item).unwrap();
+            }
+            menu.append(&message_menu).unwrap();
+        }
+        menu.append(&status_menu).unwrap();
+        menu.append(&online).unwrap();
+        menu.append(&recent).unwrap();
+        menu.append(&sound_menu).unwrap();
+        menu.append(&PredefinedMenuItem::separator()).unwrap();
+        menu.append(&quit).unwrap();
+
+        let icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip("Sonnerie Client")
+            .with_icon(load_icon())
+            .build()
+            .unwrap();
+
+        let mut tray = Self {
+            _icon: icon,
+            statuses,
+            online,
+            recent,
+            ack,
+            cancel,
+            messages,
+            mute,
+            volumes,
+            device_menu,
+            devices: Vec::new(),
+            refresh_devices: MenuItem::new("Actualiser", true, None),
+            quit,
+        };
+        tray.show_devices(devices, audio);
+        tray.show_presence(&[]);
+        tray.show_recent_rings(&[]);
+        tray
+    }
+
+    pub fn action_for(&self, event: &MenuEvent) -> Option<TrayAction> {
+        if event.id == self.quit.id() {
+            return Some(TrayAction::Quit);
+        }
+        if event.id == self.ack.id() {
+            return Some(TrayAction::Acknowledge);
+        }
+        if event.id == self.cancel.id() {
+            return Some(TrayAction::CancelRing);
+        }
+        if event.id == self.mute.id() {
+            return Some(TrayAction::SetMuted(self.mute.is_checked()));
+        }
+        if let Some((volume, _)) = self.volumes.iter().find(|(_, item)| event.id == item.id()) {
+            return Some(TrayAction::SetVolume(*volume));
+        }
+        if event.id == self.refresh_devices.id() {
+            return Some(TrayAction::RefreshDevices);
+        }
+        if let Some((device, _)) = self.devices.iter().find(|(_, item)| event.id == item.id()) {
+            return Some(TrayAction::SetDevice(device.clone()));
+        }
+        if let Some((message, _)) = self.messages.iter().find(|(_, item)| event.id == item.id()) {
+            return Some(TrayAction::Ring(message.clone()));
+        }
+        self.statuses
+            .iter()
+            .find(|(_, item)| event.id == item.id())
+            .map(|(status, _)| TrayAction::SetStatus(*status))
+    }
+
+    /// Ticks `status`, and only it; clicking a check item toggles it on its own.
+    pub fn show_status(&self, status: Status) {
+        for (s, item) in &self.statuses {
+            item.set_checked(*s == status);
+        }
+    }
+
+    /// Ticks the current volume and output.
+    pub fn show_audio(&self, audio: &AudioSettings) {
+        self.mute.set_checked(audio.muted);
+        for (v, item) in &self.volumes {
+            item.set_checked(*v == audio.volume);
+        }
+        for (device, item) in &self.devices {
+            item.set_checked(*device == audio.device);
+        }
+    }
+
+    /// Lists `devices` to choose from besides the system default one.
+    ///
+    /// A chosen device that isn't plugged in stays listed, greyed out, while sounds play on the
+    /// default one until it comes back.
+    pub fn show_devices(&mut self, devices: &[String], audio: &AudioSettings) {
+        while self.device_menu.remove_at(0).is_some() {}
+        let missing = audio
+            .device
+            .as_ref()
+            .filter(|device| !devices.contains(device));
+        self.devices = iter::once(None)
+            .chain(devices.iter().cloned().map(Some))
+            .map(|device| {
+                let label = match &device {
+                    Some(name) => name.clone(),
+                    None if missing.is_some() => "Par défaut (en attendant)".to_string(),
+                    None => "Par défaut".to_string(),
+                };
+                let checked = device == audio.device;
+                (device, CheckMenuItem::new(label, true, checked, None))
+            })
+            .collect();
+        for (_, item) in &self.devices {
+            self.device_menu.append(item).unwrap();
+        }
+        if let Some(name) = missing {
+            let label = format!("{} (débranchée)", name);
+            self.device_menu
+                .append(&CheckMenuItem::new(label, false, true, None))
+                .unwrap();
+        }
+        self.device_menu
+            .append(&PredefinedMenuItem::separator())
+            .unwrap();
+        self.device_menu.append(&self.refresh_devices).unwrap();
+    }
+
+    /// Offers to answer a ring only while there is one to answer.
+    pub fn show_pending_ring(&self, pending: bool) {
+        self.ack.set_enabled(pending);
+    }
+
+    /// Offers to cancel our own ring only while there is one to cancel.
+    pub fn show_own_ring(&self, own: bool) {
+        self.cancel.set_enabled(own);
+    }
+
+    pub fn show_presence(&self, people: &[PersonPresence]) {
+        while self.online.remove_at(0).is_some() {}
+        self.online.set_text(format!("En ligne ({})", people.len()));
+        if people.is_empty() {
+            self.online
+                .append(&MenuItem::new("Personne", false, None))
+                .unwrap();
+        }
+        for person in people {
+            let label = match person.status {
+                Status::Available => person.display_name.clone(),
+                status => format!("{} ({})", person.display_name, status_label(status)),
+            };
+            self.online
+                .append(&MenuItem::new(label, false, None))
+                .unwrap();
+        }
+    }
+
+    pub fn show_recent_rings(&self, rings: &[RingSummary]) {
+        while self.recent.remove_at(0).is_some() {}
+        if rings.is_empty() {
+            self.recent
+                .append(&MenuItem::new("Aucune", false, None))
+                .unwrap();
+        }
+        for ring in rings {
+            let time = match Local.timestamp_opt(ring.at, 0).single() {
+                Some(time) => time.format("%d/%m %H:%M").to_string(),
+                None => "?".to_string(),
+            };
+            let mut label = format!("{} — {} ({})", time, ring.sender_name, ring.room);
+            if let Some(message) = &ring.message {
+                label = format!("{} : {}", label, message);
+            }
+            self.recent
+                .append(&MenuItem::new(label, false, None))
+                .unwrap();
+        }
+    }
+}
+
+fn status_label(status: Status) -> &'static str {
+    match status {
+        Status::Available => "Disponible",
+        Status::Busy => "Occupé",
+        Status::Away => "Absent",
+    }
+}
+
+fn load_icon() -> tray_icon::Icon {
+    // Generate a simple 32x32 green/red icon manually since we might not have a file handy immediately
+    // or use a helper to load from file. For now, generated pixel buffer.
+    let (icon_rgba, icon_width, icon_height) = {
+        let width = 32;
+        let height = 32;
+        let mut rgba = Vec::with_capacity((width * height * 4) as usize);
+        for _ in 0..width * height {
+            // Greenish color
+            rgba.push(0);
+            rgba.push(255);
+            rgba.push(0);
+            rgba.push(255);
+        }
+        (rgba, width, height)
+    };
+    tray_icon::Icon::from_rgba(icon_rgba, icon_width, icon_height).expect("Failed to open icon")
+}
@@ -0,0 +1,247 @@
+use crate::auth::presented_token;
+use crate::commands::cancel_ring::{self, CancelOutcome};
+use crate::commands::ring_bell::{self, RingOutcome, Ringer};
+use crate::config::{RoomConfig, TokenConfig};
+use crate::history::{self, HistoryFilter, RingRecord};
+use crate::state::AppState;
+use axum::{
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use common::{RingBell, RingKind, RingTarget};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/ring", post(ring))
+        .route("/rings/{id}/cancel", post(cancel))
+        .route("/history", get(history))
+        .route("/history.csv", get(history_csv))
+        .route("/admin/tokens/{name}/revoke", post(revoke_token))
+        .route("/admin/rooms", get(list_rooms))
+        .route("/admin/rooms/{name}", put(put_room).delete(delete_room))
+}
+
+/// Extractor that only lets requests bearing an admin token through.
+pub struct Admin(pub TokenConfig);
+
+impl FromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let presented = presented_token(&parts.headers, &HashMap::new())
+            .ok_or((StatusCode::UNAUTHORIZED, "missing token"))?;
+        match state.auth.authenticate(&presented) {
+            Some(token) if token.admin => Ok(Admin(token)),
+            Some(_) => Err((StatusCode::FORBIDDEN, "admin token required")),
+            None => Err((StatusCode::UNAUTHORIZED, "invalid token")),
+        }
+    }
+}
+
+/// Extractor for callers of the non-admin API: any valid token, or nobody at all when
+/// auth is disabled, like on `/ws`.
+pub struct Caller(pub Option<TokenConfig>);
+
+impl FromRequestParts<Arc<AppState>> for Caller {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if !state.auth.is_enabled() {
+            return Ok(Caller(None));
+        }
+        let presented = presented_token(&parts.headers, &HashMap::new())
+            .ok_or((StatusCode::UNAUTHORIZED, "missing token"))?;
+        state
+            .auth
+            .authenticate(&presented)
+            .map(|token| Caller(Some(token)))
+            .ok_or((StatusCode::UNAUTHORIZED, "invalid token"))
+    }
+}
+
+/// Body of `POST /api/ring`; every field is optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RingRequest {
+    room: Option<String>,
+    target: Option<RingTarget>,
+    kind: RingKind,
+    message: Option<String>,
+    /// Name of a published asset to play instead of a random one.
+    sound: Option<String>,
+    available_only: bool,
+}
+
+async fn ring(
+    Caller(token): Caller,
+    State(state): State<Arc<AppState>>,
+    body: Option<Json<RingRequest>>,
+) -> Response {
+    let Json(request) = body.unwrap_or_default();
+    let ringer = api_ringer(token.as_ref());
+    let ring_request = RingBell {
+        room: request.room,
+        target: request.target,
+        kind: request.kind,
+        message: request.message,
+        available_only: request.available_only,
+        ..Default::default()
+    };
+
+    let outcome = ring_bell::ring(&ringer, &ring_request, request.sound.as_deref(), &state).await;
+    tracing::info!("API ring by {}: {:?}", ringer.name, outcome);
+    let status = match &outcome {
+        RingOutcome::Accepted { .. } | RingOutcome::NoListeners { .. } => StatusCode::OK,
+        RingOutcome::Cooldown { .. } => StatusCode::TOO_MANY_REQUESTS,
+        RingOutcome::UnknownRoom { .. } | RingOutcome::UnknownSound { .. } => StatusCode::NOT_FOUND,
+        RingOutcome::MessageTooLong { .. } => StatusCode::BAD_REQUEST,
+    };
+    let mut response = (status, Json(&outcome)).into_response();
+    if let RingOutcome::Cooldown {
+        retry_after_secs, ..
+    } = outcome
+    {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after_secs.into());
+    }
+    response
+}
+
+/// Cancels a ring started by the caller, or anyone's with an admin token.
+async fn cancel(
+    Caller(token): Caller,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response {
+    let canceller = api_ringer(token.as_ref());
+    let admin = token.as_ref().is_some_and(|token| token.admin);
+    let outcome = cancel_ring::cancel(&id, &canceller, admin, &state).await;
+    tracing::info!("API cancel by {}: {:?}", canceller.name, outcome);
+    let status = match &outcome {
+        CancelOutcome::Cancelled { .. } => StatusCode::OK,
+        CancelOutcome::UnknownRing { .. } => StatusCode::NOT_FOUND,
+        CancelOutcome::NotAllowed { .. } => StatusCode::FORBIDDEN,
+    };
+    (status, Json(&outcome)).into_response()
+}
+
+/// Who API calls act as: the token, or its bound identity if it has one.
+fn api_ringer(token: Option<&TokenConfig>) -> Ringer {
+    match token {
+        Some(token) => Ringer {
+            id: token
+                .client_id
+                .clone()
+                .unwrap_or_else(|| format!("api:{}", token.name)),
+            name: token.name.clone(),
+        },
+        None => Ringer {
+            id: "api".to_string(),
+            name: "API".to_string(),
+        },
+    }
+}
+
+async fn history(
+    _: Caller,
+    State(state): State<Arc<AppState>>,
+    Query(filter): Query<HistoryFilter>,
+) -> Json<Vec<RingRecord>> {
+    Json(state.history.query(&filter))
+}
+
+async fn history_csv(
+    _: Caller,
+    State(state): State<Arc<AppState>>,
+    Query(filter): Query<HistoryFilter>,
+) -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"history.csv\"",
+            ),
+        ],
+        history::to_csv(&state.history.query(&filter)),
+    )
+}
+
+async fn revoke_token(
+    Admin(admin): Admin,
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> StatusCode {
+    if state.auth.revoke(&name) {
+        tracing::warn!("Token {} revoked by {}", name, admin.name);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn list_rooms(_: Admin, State(state): State<Arc<AppState>>) -> Json<Vec<RoomConfig>> {
+    Json(state.rooms.list())
+}
+
+/// Settings of a room, as sent to `PUT /api/admin/rooms/{name}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoomSettings {
+    #[serde(default)]
+    cooldown_secs: Option<u64>,
+    #[serde(default)]
+    sounds: Vec<String>,
+}
+
+async fn put_room(
+    Admin(admin): Admin,
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(settings): Json<RoomSettings>,
+) -> Result<Json<RoomConfig>, (StatusCode, String)> {
+    let mut room = RoomConfig::named(&name);
+    if let Some(cooldown_secs) = settings.cooldown_secs {
+        room.cooldown_secs = cooldown_secs;
+    }
+    room.sounds = settings.sounds;
+
+    let created = state.rooms.get(&name).is_none();
+    state
+        .rooms
+        .upsert(room.clone())
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    tracing::info!(
+        "Room {} {} by {}",
+        name,
+        if created { "created" } else { "updated" },
+        admin.name
+    );
+    Ok(Json(room))
+}
+
+async fn delete_room(
+    Admin(admin): Admin,
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> StatusCode {
+    if state.rooms.remove(&name) {
+        tracing::info!("Room {} deleted by {}", name, admin.name);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
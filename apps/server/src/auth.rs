@@ -0,0 +1,129 @@
+use crate::config::{AuthConfig, TokenConfig};
+use axum::http::{header, HeaderMap};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Checks tokens presented by clients and tracks revocations.
+pub struct Auth {
+    tokens: Vec<TokenConfig>,
+    revoked: Mutex<HashSet<String>>,
+    /// Names of tokens revoked at runtime, so live sessions using them can hang up.
+    revocations: broadcast::Sender<String>,
+}
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Self {
+        let (revocations, _rx) = broadcast::channel(16);
+        Self {
+            tokens: config.tokens,
+            revoked: Mutex::new(HashSet::new()),
+            revocations,
+        }
+    }
+
+    /// Without configured tokens the server is open to anyone who can reach it.
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Finds the live token matching what the client presented.
+    pub fn authenticate(&self, presented: &str) -> Option<TokenConfig> {
+        let token = self
+            .tokens
+            .iter()
+            .find(|token| constant_time_eq(token.token.as_bytes(), presented.as_bytes()))?;
+        if self.revoked.lock().unwrap().contains(&token.name) {
+            return None;
+        }
+        Some(token.clone())
+    }
+
+    /// Refuses the token from now on and disconnects sessions using it.
+    ///
+    /// Returns `false` if no token has that name. Revocations last until restart;
+    /// remove the token from the config file to make it permanent.
+    pub fn revoke(&self, name: &str) -> bool {
+        if !self.tokens.iter().any(|token| token.name == name) {
+            return false;
+        }
+        self.revoked.lock().unwrap().insert(name.to_string());
+        let _ = self.revocations.send(name.to_string());
+        true
+    }
+
+    pub fn subscribe_revocations(&self) -> broadcast::Receiver<String> {
+        self.revocations.subscribe()
+    }
+}
+
+/// Checks that identities bound to tokens are UUIDs, like the ones the server hands out.
+pub fn validate(config: &AuthConfig) -> Result<(), String> {
+    for token in &config.tokens {
+        if let Some(id) = &token.client_id {
+            Uuid::parse_str(id).map_err(|e| {
+                format!(
+                    "token {}: client_id {:?} is not a UUID: {}",
+                    token.name, id, e
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a token from `Authorization: Bearer ...`, or `?token=` for clients
+/// that can't set headers on a WebSocket upgrade.
+pub fn presented_token(headers: &HeaderMap, query: &HashMap<String, String>) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .or_else(|| query.get("token").cloned())
+}
+
+/// Compares without bailing out at the first differing byte, so timing
+/// doesn't reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(client_id: Option<&str>) -> TokenConfig {
+        TokenConfig {
+            name: "desk".to_string(),
+            token: "change-me".to_string(),
+            client_id: client_id.map(str::to_string),
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn bound_identities_must_be_uuids() {
+        let validate = |client_id| {
+            validate(&AuthConfig {
+                tokens: vec![token(client_id)],
+            })
+        };
+
+        assert!(validate(None).is_ok());
+        assert!(validate(Some("6f1c1a52-4a8e-4b8a-9a57-0d7f3e0b2c11")).is_ok());
+        assert!(validate(Some("desk")).is_err());
+        assert!(validate(Some("")).is_err());
+    }
+
+    #[test]
+    fn constant_time_eq_matches_only_identical_bytes() {
+        assert!(constant_time_eq(b"change-me", b"change-me"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"change-me", b"change-mE"));
+        assert!(!constant_time_eq(b"change-me", b"xhange-me"));
+        assert!(!constant_time_eq(b"change-me", b"change-me-too"));
+        assert!(!constant_time_eq(b"change-me", b""));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::state::AppState;
+use crate::sync::{calculate_hash, ASSETS_DIR};
+use common::AssetEntry;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::sync::mpsc;
+
+/// Quiet period after a filesystem event before rescanning, so a file being
+/// copied in is hashed once it is complete rather than on every write.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// In-memory list of the assets the server publishes.
+#[derive(Debug, Default, Clone)]
+pub struct Catalog {
+    assets: Vec<AssetEntry>,
+    /// Modification time of each file when it was hashed, to skip unchanged files on rescan.
+    modified: HashMap<String, SystemTime>,
+}
+
+impl Catalog {
+    /// Every servable asset, sorted by name.
+    pub fn assets(&self) -> &[AssetEntry] {
+        &self.assets
+    }
+
+    pub fn is_published(&self, name: &str, hash: &str) -> bool {
+        self.assets
+            .iter()
+            .any(|asset| asset.name == name && asset.hash == hash)
+    }
+
+    pub fn hash_of(&self, name: &str) -> Option<&str> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.hash.as_str())
+    }
+
+    /// Picks a random sound among `names`, or among all assets if none of them is published.
+    pub fn random_hash_among(&self, names: &[String]) -> Option<&str> {
+        let pool: Vec<&AssetEntry> = self
+            .assets
+            .iter()
+            .filter(|asset| names.contains(&asset.name))
+            .collect();
+        let pool = if pool.is_empty() {
+            self.assets.iter().collect()
+        } else {
+            pool
+        };
+        pool.choose(&mut rand::thread_rng())
+            .map(|asset| asset.hash.as_str())
+    }
+
+    /// Previously computed hash for a file, if it hasn't changed since.
+    fn known_hash(&self, name: &str, size: u64, modified: SystemTime) -> Option<&str> {
+        if self.modified.get(name) != Some(&modified) {
+            return None;
+        }
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name && asset.size == size)
+            .map(|asset| asset.hash.as_str())
+    }
+}
+
+/// Lists the assets directory, only rehashing files that changed since `previous`.
+pub async fn scan(previous: &Catalog) -> std::io::Result<Catalog> {
+    let mut catalog = Catalog::default();
+    let path = Path::new(ASSETS_DIR);
+
+    if !path.exists() {
+        fs::create_dir_all(path).await?;
+        return Ok(catalog);
+    }
+
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        let Some(filename) = path.file_name().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if !(filename.ends_with(".mp3") || filename.ends_with(".wav")) {
+            continue;
+        }
+
+        let metadata = entry.metadata().await?;
+        let modified = metadata.modified()?;
+        let hash = match previous.known_hash(filename, metadata.len(), modified) {
+            Some(hash) => hash.to_string(),
+            None => match calculate_hash(&path).await {
+                Ok(hash) => hash,
+                Err(_) => continue,
+            },
+        };
+
+        catalog.modified.insert(filename.to_string(), modified);
+        catalog.assets.push(AssetEntry {
+            name: filename.to_string(),
+            hash,
+            size: metadata.len(),
+        });
+    }
+    catalog.assets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(catalog)
+}
+
+/// Watches the assets directory and refreshes the catalog in `state` on change.
+///
+/// The returned watcher must be kept alive for as long as updates are wanted.
+pub fn watch(state: Arc<AppState>) -> notify::Result<RecommendedWatcher> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok() {
+            let _ = tx.send(());
+        }
+    })?;
+    watcher.watch(Path::new(ASSETS_DIR), RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+            refresh(&state).await;
+        }
+    });
+    Ok(watcher)
+}
+
+async fn refresh(state: &AppState) {
+    let current = state.catalog.borrow().clone();
+    let updated = match scan(&current).await {
+        Ok(updated) => updated,
+        Err(e) => {
+            tracing::warn!("Failed to rescan assets: {}", e);
+            return;
+        }
+    };
+
+    if updated.assets == current.assets {
+        // Only timestamps moved; remember them without bothering clients.
+        state.catalog.send_if_modified(|catalog| {
+            *catalog = Arc::new(updated);
+            false
+        });
+    } else {
+        tracing::info!(
+            "Assets changed ({} published), pushing manifest to clients",
+            updated.assets.len()
+        );
+        state.catalog.send_replace(Arc::new(updated));
+    }
+}
@@ -0,0 +1,43 @@
+use crate::handshake::Session;
+use crate::state::AppState;
+use crate::webhooks::WebhookEvent;
+use common::{Ack, Acknowledged, WsMessage};
+use std::iter;
+
+/// Tells the ringer and everyone the ring reached that `session` is handling it.
+pub async fn handle_ack(ack: &Ack, session: &Session, state: &AppState) {
+    let ring = match state.active_rings.acknowledge(
+        &ack.ring_id,
+        &session.client_id,
+        &session.display_name,
+    ) {
+        Ok(ring) => ring,
+        Err(reason) => {
+            tracing::warn!("Ignoring ack for ring {}: {}", ack.ring_id, reason);
+            return;
+        }
+    };
+    tracing::info!(
+        "{} is answering ring {} from {}",
+        session.display_name,
+        ack.ring_id,
+        ring.sender_name
+    );
+
+    state
+        .history
+        .acknowledge(&ack.ring_id, &session.display_name);
+    state.webhooks.fire(&WebhookEvent::Ack {
+        ring_id: ack.ring_id.clone(),
+        room: ring.room.clone(),
+        sender_name: ring.sender_name.clone(),
+        acknowledged_by: session.display_name.clone(),
+    });
+
+    let msg = WsMessage::Acknowledged(Acknowledged {
+        ring_id: ack.ring_id.clone(),
+        client_id: session.client_id.clone(),
+        display_name: session.display_name.clone(),
+    });
+    state.send_to(iter::once(&ring.sender_id).chain(&ring.recipients), &msg);
+}
@@ -0,0 +1,82 @@
+use crate::commands::ring_bell::Ringer;
+use crate::handshake::Session;
+use crate::state::AppState;
+use crate::webhooks::WebhookEvent;
+use common::{CancelRing, RingCancelled, WsMessage};
+use serde::Serialize;
+use std::iter;
+
+/// What became of a cancel request; also the body `POST /api/rings/{id}/cancel` answers with.
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum CancelOutcome {
+    Cancelled {
+        ring_id: String,
+        room: String,
+    },
+    /// No such ring, or it is too old to matter anymore.
+    UnknownRing {
+        ring_id: String,
+    },
+    /// Only the ringer or an admin may cancel a ring.
+    NotAllowed {
+        ring_id: String,
+    },
+}
+
+/// Cancels a ring over the socket on behalf of `session`.
+pub async fn handle_cancel_ring(request: &CancelRing, session: &Session, state: &AppState) {
+    match cancel(
+        &request.ring_id,
+        &Ringer::from(session),
+        session.admin,
+        state,
+    )
+    .await
+    {
+        CancelOutcome::Cancelled { ring_id, room } => {
+            tracing::info!("Cancelled ring {} in room {}", ring_id, room)
+        }
+        other => tracing::warn!("Ring not cancelled: {:?}", other),
+    }
+}
+
+/// Stops ring `ring_id` on behalf of `canceller`, telling the ringer and everyone it reached.
+///
+/// Cancelled rings can no longer be answered nor escalated.
+pub async fn cancel(
+    ring_id: &str,
+    canceller: &Ringer,
+    admin: bool,
+    state: &AppState,
+) -> CancelOutcome {
+    let ring_id = ring_id.to_string();
+    let Some(ring) = state.active_rings.get(&ring_id) else {
+        return CancelOutcome::UnknownRing { ring_id };
+    };
+    if ring.sender_id != canceller.id && !admin {
+        return CancelOutcome::NotAllowed { ring_id };
+    }
+    // Someone else may have cancelled it in the meantime.
+    if state.active_rings.remove(&ring_id).is_none() {
+        return CancelOutcome::UnknownRing { ring_id };
+    }
+
+    state.history.cancel(&ring_id, &canceller.name);
+    state.webhooks.fire(&WebhookEvent::Cancel {
+        ring_id: ring_id.clone(),
+        room: ring.room.clone(),
+        sender_name: ring.sender_name.clone(),
+        cancelled_by: canceller.name.clone(),
+    });
+
+    let msg = WsMessage::RingCancelled(RingCancelled {
+        ring_id: ring_id.clone(),
+        by: canceller.name.clone(),
+    });
+    state.send_to(iter::once(&ring.sender_id).chain(&ring.recipients), &msg);
+    CancelOutcome::Cancelled {
+        ring_id,
+        room: ring.room,
+    }
+}
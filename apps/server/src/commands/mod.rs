@@ -1,2 +1,4 @@
+pub mod ack;
+pub mod cancel_ring;
 pub mod ring_bell;
 pub mod sync;
@@ -1,49 +1,322 @@
-use crate::state::AppState;
-use common::WsMessage;
-use rand::seq::SliceRandom;
+use crate::handshake::Session;
+use crate::history::RingRecord;
+use crate::rings::ActiveRing;
+use crate::state::{AppState, Broadcast};
+use crate::webhooks::WebhookEvent;
+use chrono::Utc;
+use common::{RingBell, RingSent, RingTarget, Urgency, WsMessage};
+use serde::Serialize;
+use std::collections::BTreeSet;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-pub async fn handle_ring_bell(
-    sender_id: Option<String>,
+/// Longest ring message accepted, in characters.
+const MAX_MESSAGE_LEN: usize = 140;
+
+/// Whoever asked for a ring: a connected client, or a caller of the REST API.
+pub struct Ringer {
+    /// Identity cooldowns are keyed on and rings are stamped with.
+    pub id: String,
+    pub name: String,
+}
+
+impl From<&Session> for Ringer {
+    fn from(session: &Session) -> Self {
+        Self {
+            id: session.client_id.clone(),
+            name: session.display_name.clone(),
+        }
+    }
+}
+
+/// What became of a ring request; also the body `POST /api/ring` answers with.
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum RingOutcome {
+    Accepted {
+        ring_id: String,
+        room: String,
+        listeners: usize,
+    },
+    Cooldown {
+        room: String,
+        retry_after_secs: u64,
+    },
+    NoListeners {
+        room: String,
+    },
+    UnknownRoom {
+        room: String,
+    },
+    UnknownSound {
+        sound: String,
+    },
+    MessageTooLong {
+        max_len: usize,
+    },
+}
+
+/// Rings over the socket on behalf of `session`; its server-assigned identity is the only one
+/// trusted. Once the ring is out, the session is told its ID so it can cancel it.
+pub async fn handle_ring_bell(session: &Session, request: &RingBell, state: &Arc<AppState>) {
+    match ring(&Ringer::from(session), request, None, state).await {
+        RingOutcome::Accepted {
+            ring_id,
+            room,
+            listeners,
+        } => {
+            tracing::info!("Rang room {} for {} listeners", room, listeners);
+            let sent = WsMessage::RingSent(RingSent {
+                ring_id,
+                room,
+                listeners,
+            });
+            state.send_to([&session.client_id], &sent);
+        }
+        RingOutcome::Cooldown { room, .. } => tracing::warn!(
+            "Cooldown active for user {} in room {}, ignoring ring.",
+            session.client_id,
+            room
+        ),
+        other => tracing::warn!("Ring not delivered: {:?}", other),
+    }
+}
+
+/// Rings everyone in the requested room, or only the request's target, on behalf of `ringer`.
+///
+/// `sound` names a published asset to play instead of a random one from the kind's, or else the
+/// room's, sounds. Each kind has its own cooldown. Nothing is sent, and no cooldown started,
+/// unless someone will hear it. Unanswered rings are escalated afterwards, see
+/// [`crate::escalation`].
+pub async fn ring(
+    ringer: &Ringer,
+    request: &RingBell,
+    sound: Option<&str>,
     state: &Arc<AppState>,
-    tx: &broadcast::Sender<String>,
-) {
-    // 0. Cooldown check
-    if let Some(id) = &sender_id {
-        let mut last_trigger = state.last_trigger.lock().unwrap();
-        let now = std::time::Instant::now();
-        if let Some(last) = last_trigger.get(id) {
-            if now.duration_since(*last) < std::time::Duration::from_secs(10) {
-                tracing::warn!("Cooldown active for user {}, ignoring ring.", id);
-                return;
+) -> RingOutcome {
+    let Some(room) = state.rooms.resolve(request.room.as_deref()) else {
+        return RingOutcome::UnknownRoom {
+            room: request.room.clone().unwrap_or_default(),
+        };
+    };
+
+    let message = match request.message.as_deref().map(clean_message) {
+        Some(message) if message.chars().count() > MAX_MESSAGE_LEN => {
+            return RingOutcome::MessageTooLong {
+                max_len: MAX_MESSAGE_LEN,
+            }
+        }
+        Some(message) if !message.is_empty() => Some(message),
+        _ => None,
+    };
+
+    let kind = state.kinds.get(&request.kind).cloned().unwrap_or_default();
+    let pool = if kind.sounds.is_empty() {
+        &room.sounds
+    } else {
+        &kind.sounds
+    };
+    // Critical rings reach everyone, whatever their status.
+    let available_only = request.available_only && request.kind.urgency() < Urgency::Critical;
+
+    // 0. Pick the requested sound, or a random published one preferring the kind's or room's own
+    let chosen_hash = {
+        let catalog = state.catalog.borrow();
+        match sound {
+            Some(name) => match catalog.hash_of(name) {
+                Some(hash) => Some(hash.to_string()),
+                None => {
+                    return RingOutcome::UnknownSound {
+                        sound: name.to_string(),
+                    }
+                }
+            },
+            None => catalog.random_hash_among(pool).map(str::to_string),
+        }
+    };
+    match &chosen_hash {
+        Some(hash) => tracing::info!("Server selected sound hash: {}", hash),
+        None => tracing::warn!("No assets found on server, sending empty hash"),
+    }
+
+    // 1. Find who will hear it
+    let recipients = match &request.target {
+        Some(target) => {
+            let mut recipients = resolve_target(target, state);
+            if available_only {
+                recipients.retain(|recipient| state.is_available(recipient));
             }
+            recipients.remove(&ringer.id);
+            recipients
         }
-        last_trigger.insert(id.clone(), now);
+        None => state.listeners(&room.name, available_only, &ringer.id),
+    };
+    let listeners = recipients.len();
+    if listeners == 0 {
+        return RingOutcome::NoListeners { room: room.name };
     }
 
-    // 1. Get all server hashes
-    let hashes_map = crate::sync::get_server_hashes().await.unwrap_or_default();
+    // 2. Cooldown check, per room and kind
+    {
+        let mut last_trigger = state.last_trigger.lock().unwrap();
+        let now = Instant::now();
+        let key = (room.name.clone(), request.kind, ringer.id.clone());
+        let cooldown = Duration::from_secs(kind.cooldown_secs.unwrap_or(room.cooldown_secs));
+        if let Some(last) = last_trigger.get(&key) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < cooldown {
+                let remaining = cooldown - elapsed;
+                let retry_after_secs =
+                    remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                state.webhooks.fire(&WebhookEvent::Cooldown {
+                    room: room.name.clone(),
+                    sender_id: ringer.id.clone(),
+                    sender_name: ringer.name.clone(),
+                    kind: request.kind,
+                    retry_after_secs,
+                });
+                return RingOutcome::Cooldown {
+                    room: room.name,
+                    retry_after_secs,
+                };
+            }
+        }
+        last_trigger.insert(key, now);
+    }
 
-    // 2. Pick a random hash if available
-    let values: Vec<&String> = hashes_map.values().collect();
-    let mut chosen_hash: Option<String> = None;
+    // 3. Construct message
+    let ring_id = Uuid::new_v4().to_string();
+    let msg = WsMessage::RingBell(RingBell {
+        ring_id: Some(ring_id.clone()),
+        room: Some(room.name.clone()),
+        target: request.target.clone(),
+        kind: request.kind,
+        available_only,
+        sender_id: Some(ringer.id.clone()),
+        sender_name: Some(ringer.name.clone()),
+        sound_hash: chosen_hash.clone(),
+        message: message.clone(),
+        attempt: 0,
+    });
 
-    if let Some(hash) = values.choose(&mut rand::thread_rng()) {
-        chosen_hash = Some(hash.to_string());
-        tracing::info!("Server selected sound hash: {}", hash);
+    // 4. Deliver to the target directly, or broadcast to the room's subscribers
+    if request.target.is_some() {
+        state.send_to(&recipients, &msg);
     } else {
-        tracing::warn!("No assets found on server, sending empty hash");
+        let _ = state.tx.send(Broadcast {
+            room: Some(room.name.clone()),
+            available_only,
+            text: msg.to_text(),
+        });
     }
+    state.active_rings.start(
+        &ring_id,
+        ActiveRing {
+            room: room.name.clone(),
+            sender_id: ringer.id.clone(),
+            sender_name: ringer.name.clone(),
+            kind: request.kind,
+            target: request.target.clone(),
+            available_only,
+            sound_hash: chosen_hash.clone(),
+            message: message.clone(),
+            attempt: 0,
+            recipients,
+            acknowledged_by: Vec::new(),
+        },
+    );
+    crate::escalation::schedule(state.clone(), ring_id.clone());
+    state.history.record(RingRecord {
+        id: ring_id.clone(),
+        at: Utc::now(),
+        room: room.name.clone(),
+        sender_id: ringer.id.clone(),
+        sender_name: ringer.name.clone(),
+        kind: request.kind,
+        sound_hash: chosen_hash.clone(),
+        target: request.target.clone(),
+        message: message.clone(),
+        listeners,
+        acknowledged_by: Vec::new(),
+        cancelled_by: None,
+    });
+    state.webhooks.fire(&WebhookEvent::Ring {
+        ring_id: ring_id.clone(),
+        room: room.name.clone(),
+        sender_id: ringer.id.clone(),
+        sender_name: ringer.name.clone(),
+        kind: request.kind,
+        sound_hash: chosen_hash,
+        target: request.target.clone(),
+        message,
+        listeners,
+    });
+    RingOutcome::Accepted {
+        ring_id,
+        room: room.name,
+        listeners,
+    }
+}
 
-    // 3. Construct message
-    let mut msg = WsMessage::ring_bell(sender_id);
-    // Overwrite data with the hash string (as JSON string)
-    if let Some(h) = chosen_hash {
-        msg.data = Some(serde_json::to_value(h).unwrap());
+/// Identities of the live connections a targeted ring should reach.
+pub fn resolve_target(target: &RingTarget, state: &AppState) -> BTreeSet<String> {
+    let people = match target {
+        RingTarget::Person(who) => vec![who.as_str()],
+        RingTarget::Group(name) => match state.groups.iter().find(|group| &group.name == name) {
+            Some(group) => group.members.iter().map(String::as_str).collect(),
+            None => {
+                tracing::warn!("Ring for unknown group {}", name);
+                Vec::new()
+            }
+        },
+    };
+    people
+        .into_iter()
+        .flat_map(|who| state.find_connections(who))
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Makes a ring message safe to show on one line: control characters, line breaks and text
+/// direction overrides become spaces, and runs of whitespace collapse.
+fn clean_message(message: &str) -> String {
+    message
+        .split(|c: char| c.is_whitespace() || c.is_control() || is_direction_override(c))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn is_direction_override(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_message_keeps_ordinary_text() {
+        assert_eq!(clean_message("Colis à l'accueil"), "Colis à l'accueil");
+        assert_eq!(clean_message("<b>& co</b>"), "<b>& co</b>");
     }
 
-    // 4. Broadcast
-    let text = serde_json::to_string(&msg).unwrap();
-    let _ = tx.send(text);
+    #[test]
+    fn clean_message_puts_everything_on_one_line() {
+        assert_eq!(clean_message("  on\nmange\r\n ? "), "on mange ?");
+        assert_eq!(clean_message("a\tb\u{0}c\u{1b}[31md"), "a b c [31md");
+        assert_eq!(clean_message("a\u{2028}b\u{85}c"), "a b c");
+    }
+
+    #[test]
+    fn clean_message_drops_direction_overrides() {
+        assert_eq!(clean_message("abc\u{202E}fed\u{202C}"), "abc fed");
+        assert_eq!(clean_message("\u{2066}x\u{2069}\u{200F}"), "x");
+    }
+
+    #[test]
+    fn clean_message_can_leave_nothing() {
+        assert_eq!(clean_message(" \n\u{202E} "), "");
+    }
 }
@@ -1,19 +1,86 @@
-use common::{SyncRequest, WsMessage};
+use crate::catalog::Catalog;
+use crate::handshake::Session;
+use crate::state::AppState;
+use axum::extract::ws::Message;
+use common::transfer::{encode_asset, AssetHeader, CHUNK_SIZE};
+use common::{FetchAssets, WsMessage};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::mpsc;
 
-pub async fn handle_sync(request: SyncRequest, local_tx: &mpsc::Sender<String>) {
-    let server_hashes = crate::sync::get_server_hashes().await.unwrap_or_default();
+/// Lists the published assets this client can play; the client works out what to fetch.
+pub fn manifest_for(session: &Session, catalog: &Catalog) -> WsMessage {
+    let assets = catalog
+        .assets()
+        .iter()
+        .filter(|asset| session.accepts_file(&asset.name))
+        .cloned()
+        .collect();
+    WsMessage::manifest(assets)
+}
+
+pub async fn handle_fetch(
+    request: FetchAssets,
+    session: &Session,
+    state: &Arc<AppState>,
+    bulk_tx: &mpsc::Sender<Message>,
+) {
+    let catalog = state.catalog.borrow().clone();
+
+    for fetch in request.assets {
+        // Only serve what is currently published, under the hash the client expects.
+        if !catalog.is_published(&fetch.name, &fetch.hash) || !session.accepts_file(&fetch.name) {
+            tracing::warn!(
+                "Client asked for unknown asset {} ({})",
+                fetch.name,
+                fetch.hash
+            );
+            continue;
+        }
+
+        tracing::info!(
+            "Sending {} to client (resuming at {} bytes)",
+            fetch.name,
+            fetch.offset
+        );
+        if let Err(e) = send_file(&fetch.name, &fetch.hash, fetch.offset, bulk_tx).await {
+            tracing::warn!("Failed to send {}: {}", fetch.name, e);
+        }
+    }
+}
+
+/// Streams a file as binary chunks starting at `offset`, via the connection's bulk channel.
+async fn send_file(
+    filename: &str,
+    hash: &str,
+    offset: u64,
+    bulk_tx: &mpsc::Sender<Message>,
+) -> std::io::Result<()> {
+    let mut file = crate::sync::open_asset(filename).await?;
+    let total = file.metadata().await?.len();
+    // A partial download longer than the file can't be ours, start over.
+    let mut offset = if offset <= total { offset } else { 0 };
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let count = file.read(&mut buffer).await?;
+        let header = AssetHeader {
+            filename: filename.to_string(),
+            hash: hash.to_string(),
+            total,
+            offset,
+            length: count as u64,
+        };
+        let frame = encode_asset(&header, &buffer[..count]);
+        if bulk_tx.send(Message::Binary(frame.into())).await.is_err() {
+            // Client went away; it will resume from its partial file next time.
+            return Ok(());
+        }
 
-    for (filename, server_hash) in server_hashes {
-        let client_hash = request.hashes.get(&filename);
-        if client_hash != Some(&server_hash) {
-            tracing::info!("Client needs update for {}", filename);
-            // Send file via local channel
-            if let Ok(content) = crate::sync::read_file_content(&filename).await {
-                let msg = WsMessage::file_transfer(filename, content);
-                let json = serde_json::to_string(&msg).unwrap();
-                let _ = local_tx.send(json).await;
-            }
+        offset += count as u64;
+        if count == 0 || header.is_last() {
+            return Ok(());
         }
     }
 }
@@ -0,0 +1,192 @@
+use common::RingKind;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const DEFAULT_CONFIG_PATH: &str = "server.toml";
+
+/// Server settings, read from `server.toml` or the file named by `SERVER_CONFIG`.
+///
+/// Every section is optional; a missing file means defaults everywhere.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub auth: AuthConfig,
+    /// Independent doorbells; the first one is the default. Without any, a
+    /// single `general` room is used.
+    pub rooms: Vec<RoomConfig>,
+    /// Per-kind overrides of the room settings, e.g. `[kinds.lunch]`.
+    pub kinds: HashMap<RingKind, KindConfig>,
+    /// Named sets of people that can be rung together.
+    pub groups: Vec<GroupConfig>,
+    /// URLs told about rings as they happen.
+    pub webhooks: Vec<WebhookConfig>,
+    pub history: HistoryConfig,
+    pub identity: IdentityConfig,
+    pub escalation: EscalationConfig,
+}
+
+/// Re-ringing, louder and wider, when nobody answers.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EscalationConfig {
+    /// Seconds to wait for an answer before each re-ring.
+    pub after_secs: u64,
+    /// Re-rings after the first one, at most; 0 turns escalation off.
+    pub max_attempts: u32,
+    /// Asset name re-rings play; the ring's own sound if unset.
+    pub sound: Option<String>,
+}
+
+impl Default for EscalationConfig {
+    fn default() -> Self {
+        Self {
+            after_secs: 60,
+            max_attempts: 0,
+            sound: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// JSON Lines file every delivered ring is appended to.
+    pub path: PathBuf,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("history.jsonl"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// File holding the key that signs client identities, generated if missing.
+    pub key_path: PathBuf,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            key_path: PathBuf::from("identity.key"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Tokens accepted on `/ws`. With none configured, anyone may connect.
+    pub tokens: Vec<TokenConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// Label used in logs and to revoke the token.
+    pub name: String,
+    pub token: String,
+    /// Ties the token to one identity, a UUID: clients using it always get this ID.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Allows calling the admin API.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    pub name: String,
+    /// Minimum time between two rings from the same client in this room.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    /// Asset names to pick the ring sound from; empty means any published asset.
+    #[serde(default)]
+    pub sounds: Vec<String>,
+}
+
+impl RoomConfig {
+    pub fn named(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            cooldown_secs: default_cooldown_secs(),
+            sounds: Vec::new(),
+        }
+    }
+}
+
+fn default_cooldown_secs() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KindConfig {
+    /// Asset names to pick this kind's sound from; the room's sounds if empty.
+    pub sounds: Vec<String>,
+    /// Minimum time between two rings of this kind from the same client in a room; the room's
+    /// cooldown if unset.
+    pub cooldown_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub name: String,
+    /// Client IDs or display names.
+    pub members: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key for the `X-Sonnerie-Signature` HMAC-SHA256 header; requests are unsigned without one.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Events to send, see [`crate::webhooks::EVENTS`]; all of them if empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// JSON body with `{{field}}` placeholders; the event's own JSON if unset.
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let (path, explicit) = match std::env::var("SERVER_CONFIG") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+                tracing::info!("No {} found, using default settings", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+        };
+        let config: Self =
+            toml::from_str(&text).map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        crate::auth::validate(&config.auth)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        crate::rooms::validate(&config.rooms)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        crate::webhooks::validate(&config.webhooks)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+}
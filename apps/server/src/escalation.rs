@@ -0,0 +1,113 @@
+use crate::commands::ring_bell::resolve_target;
+use crate::rings::ActiveRing;
+use crate::state::AppState;
+use crate::webhooks::WebhookEvent;
+use common::{RingBell, WsMessage};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Re-rings `ring_id` every [`EscalationConfig::after_secs`] until someone answers, up to
+/// [`EscalationConfig::max_attempts`] times.
+///
+/// The first re-ring plays the escalation sound to the same people; each later one widens the
+/// audience one step: from a person or group to their whole room, then to the busy and away too.
+///
+/// [`EscalationConfig::after_secs`]: crate::config::EscalationConfig::after_secs
+/// [`EscalationConfig::max_attempts`]: crate::config::EscalationConfig::max_attempts
+pub fn schedule(state: Arc<AppState>, ring_id: String) {
+    let settings = &state.escalation;
+    if settings.max_attempts == 0 {
+        return;
+    }
+    let delay = Duration::from_secs(settings.after_secs);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(delay).await;
+            let Some(ring) = state.active_rings.unanswered(&ring_id) else {
+                return;
+            };
+            if ring.attempt >= state.escalation.max_attempts {
+                tracing::info!("Ring {} was never answered", ring_id);
+                return;
+            }
+            if !escalate(&state, &ring_id, ring).await {
+                return;
+            }
+        }
+    });
+}
+
+/// Rings again, returning `false` when there is nobody left to ring.
+async fn escalate(state: &AppState, ring_id: &str, mut ring: ActiveRing) -> bool {
+    ring.attempt += 1;
+    if ring.attempt > 1 {
+        widen(&mut ring);
+    }
+    if let Some(hash) = escalation_sound(state) {
+        ring.sound_hash = Some(hash);
+    }
+
+    let mut recipients = match &ring.target {
+        Some(target) => resolve_target(target, state),
+        None => state.listeners(&ring.room, ring.available_only, &ring.sender_id),
+    };
+    if ring.target.is_some() && ring.available_only {
+        recipients.retain(|recipient| state.is_available(recipient));
+    }
+    recipients.remove(&ring.sender_id);
+    if recipients.is_empty() {
+        tracing::info!("Nobody left to escalate ring {} to", ring_id);
+        return false;
+    }
+
+    tracing::info!(
+        "Ring {} unanswered, ringing {} listeners again (attempt {})",
+        ring_id,
+        recipients.len(),
+        ring.attempt
+    );
+    let msg = WsMessage::RingBell(RingBell {
+        ring_id: Some(ring_id.to_string()),
+        room: Some(ring.room.clone()),
+        target: ring.target.clone(),
+        kind: ring.kind,
+        available_only: ring.available_only,
+        sender_id: Some(ring.sender_id.clone()),
+        sender_name: Some(ring.sender_name.clone()),
+        sound_hash: ring.sound_hash.clone(),
+        message: ring.message.clone(),
+        attempt: ring.attempt,
+    });
+    state.send_to(&recipients, &msg);
+    state.webhooks.fire(&WebhookEvent::Escalate {
+        ring_id: ring_id.to_string(),
+        room: ring.room.clone(),
+        sender_name: ring.sender_name.clone(),
+        kind: ring.kind,
+        attempt: ring.attempt,
+        target: ring.target.clone(),
+        message: ring.message.clone(),
+        listeners: recipients.len(),
+    });
+
+    // Whoever heard an earlier attempt may still answer it.
+    ring.recipients.extend(recipients);
+    state.active_rings.update(ring_id, ring);
+    true
+}
+
+/// Moves the ring one step closer to everyone in its room.
+fn widen(ring: &mut ActiveRing) {
+    if ring.target.take().is_none() {
+        ring.available_only = false;
+    }
+}
+
+fn escalation_sound(state: &AppState) -> Option<String> {
+    let name = state.escalation.sound.as_deref()?;
+    let hash = state.catalog.borrow().hash_of(name).map(str::to_string);
+    if hash.is_none() {
+        tracing::warn!("Escalation sound {} is not published", name);
+    }
+    hash
+}
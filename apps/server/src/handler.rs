@@ -1,86 +1,262 @@
+use crate::auth::presented_token;
 use crate::commands;
-use crate::state::AppState;
+use crate::config::TokenConfig;
+use crate::handshake;
+use crate::state::{AppState, Link};
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
     },
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
-use common::WsMessage;
+use common::{Presence, RingHistory, RingSummary, RoomSubscription, WsMessage};
 use futures::{sink::SinkExt, stream::StreamExt};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Rings a client is told about on connection, to fill its tray.
+const RECENT_RINGS: usize = 10;
+
+/// Asset chunks queued for one client ahead of its socket.
+const BULK_FRAMES: usize = 8;
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+) -> Response {
+    // Tokens are checked before the upgrade so unauthenticated clients never get a socket.
+    let token = if state.auth.is_enabled() {
+        let Some(presented) = presented_token(&headers, &query) else {
+            tracing::warn!("Refused WebSocket upgrade without token");
+            return (StatusCode::UNAUTHORIZED, "missing token").into_response();
+        };
+        let Some(token) = state.auth.authenticate(&presented) else {
+            tracing::warn!("Refused WebSocket upgrade with invalid or revoked token");
+            return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+        };
+        Some(token)
+    } else {
+        None
+    };
+
+    ws.on_upgrade(|socket| handle_socket(socket, state, token))
+}
+
+/// Aborts a task when the handle goes away.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
-async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
+async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>, token: Option<TokenConfig>) {
+    // Subscribed first so nothing said after we are registered is missed.
     let mut rx = state.tx.subscribe();
+    // Local channel for sending unicast messages to this client
+    let (local_tx, mut local_rx) = mpsc::channel::<Message>(100);
+    // Asset chunks, queued apart so a sync never fills the channel above
+    let (bulk_tx, mut bulk_rx) = mpsc::channel::<Message>(BULK_FRAMES);
+    let link = Link::new(local_tx.clone());
+    let superseded = link.superseded.clone();
+    // Held until we return, releasing the identity on every way out of here.
+    let (session, _claim) = match handshake::negotiate(&mut socket, &state, token, link).await {
+        Ok(accepted) => accepted,
+        Err(reason) => {
+            tracing::warn!("Rejected client: {}", reason);
+            return;
+        }
+    };
+    // Every log line of this connection carries the identity we assigned it.
+    let span = tracing::info_span!("client", id = %session.client_id);
+    span.in_scope(|| {
+        tracing::info!(
+            "{} connected with client {} (formats: {:?}, features: {:?})",
+            session.display_name,
+            session.client_version,
+            session.audio_formats,
+            session.features
+        )
+    });
+
     let (mut sender, mut receiver) = socket.split();
-    let tx = state.tx.clone();
 
-    // Local channel for sending unicast messages to this client
-    let (local_tx, mut local_rx) = mpsc::channel::<String>(100);
+    // Who is already here; joins, leaves and status changes after this come as events.
+    let presence = WsMessage::Presence(Presence {
+        people: state.presence(),
+    });
+    let _ = local_tx.try_send(Message::Text(presence.to_text().into()));
+    let history = WsMessage::History(RingHistory {
+        rings: state
+            .history
+            .recent(&session.rooms, RECENT_RINGS)
+            .into_iter()
+            .map(|record| RingSummary {
+                message: record.message.clone(),
+                room: record.room,
+                sender_name: record.sender_name,
+                at: record.at.timestamp(),
+            })
+            .collect(),
+    });
+    let _ = local_tx.try_send(Message::Text(history.to_text().into()));
 
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            if let Message::Text(text) = msg {
-                match serde_json::from_str::<WsMessage>(&text) {
-                    Ok(parsed) => {
-                        if parsed.event == "ring_bell" {
+    let send_state = state.clone();
+
+    let mut catalog_rx = state.catalog.subscribe();
+    let mut revocations = state.auth.subscribe_revocations();
+    let send_session = session.clone();
+
+    let mut recv_task = tokio::spawn(
+        async move {
+            // Asset transfer in progress, streamed beside the loop so it keeps reading messages.
+            // Dropped with this task however it ends, which stops the transfer too.
+            let mut transfer: Option<AbortOnDrop> = None;
+            while let Some(Ok(msg)) = receiver.next().await {
+                if let Message::Text(text) = msg {
+                    match WsMessage::from_text(&text) {
+                        Ok(WsMessage::RingBell(ring)) => {
                             tracing::info!("Received ring_bell, broadcasting...");
-                            commands::ring_bell::handle_ring_bell(parsed.sender_id, &state, &tx)
+                            commands::ring_bell::handle_ring_bell(&session, &ring, &state).await;
+                        }
+                        Ok(WsMessage::CancelRing(request)) => {
+                            commands::cancel_ring::handle_cancel_ring(&request, &session, &state)
                                 .await;
-                        } else if parsed.event == "sync_hashes" {
-                            tracing::info!("Received sync_hashes, checking diff...");
-                            if let Some(data) = parsed.data {
-                                if let Ok(request) =
-                                    serde_json::from_value::<common::SyncRequest>(data)
-                                {
-                                    commands::sync::handle_sync(request, &local_tx).await;
+                        }
+                        Ok(WsMessage::Ack(ack)) => {
+                            commands::ack::handle_ack(&ack, &session, &state).await;
+                        }
+                        Ok(WsMessage::Subscribe(request)) => {
+                            let rooms = state.rooms.existing(&request.rooms);
+                            tracing::info!("Subscribed to rooms {:?}", rooms);
+                            let reply = WsMessage::Subscribed(RoomSubscription {
+                                rooms: rooms.iter().cloned().collect(),
+                            });
+                            state.subscribe(&session.client_id, rooms);
+                            let _ = local_tx.send(Message::Text(reply.to_text().into())).await;
+                        }
+                        Ok(WsMessage::SetStatus(request)) => {
+                            tracing::info!("Status set to {:?}", request.status);
+                            state.set_status(&session.client_id, request.status);
+                        }
+                        Ok(WsMessage::FetchAssets(request)) => {
+                            tracing::info!(
+                                "Received fetch_assets for {} files",
+                                request.assets.len()
+                            );
+                            // One transfer at a time: a new request waits for the previous one.
+                            let previous = transfer.take();
+                            let (session, state, bulk_tx) =
+                                (session.clone(), state.clone(), bulk_tx.clone());
+                            transfer = Some(AbortOnDrop(tokio::spawn(
+                                async move {
+                                    if let Some(mut previous) = previous {
+                                        let _ = (&mut previous.0).await;
+                                    }
+                                    commands::sync::handle_fetch(
+                                        request, &session, &state, &bulk_tx,
+                                    )
+                                    .await;
                                 }
-                            }
+                                .in_current_span(),
+                            )));
                         }
-                    }
-                    Err(_) => {
-                        // Fallback for raw string
-                        if text.trim() == "ring_bell" {
-                            tracing::info!("Received raw ring_bell, broadcasting...");
-                            let msg = WsMessage::ring_bell(None);
-                            commands::ring_bell::handle_ring_bell(msg.sender_id, &state, &tx).await;
+                        Ok(other) => {
+                            tracing::warn!("Ignoring unexpected message from client: {:?}", other);
+                        }
+                        Err(error) => {
+                            tracing::warn!("Rejected message from client: {}", error);
+                            let reply = WsMessage::error(error).to_text();
+                            let _ = local_tx.send(Message::Text(reply.into())).await;
                         }
                     }
                 }
             }
         }
-    });
+        .instrument(span.clone()),
+    );
 
-    let mut send_task = tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                // Broadcast messages
-                Ok(msg) = rx.recv() => {
-                    if sender.send(Message::Text(msg.into())).await.is_err() {
-                        break;
+    let mut send_task = tokio::spawn(
+        async move {
+            let manifest =
+                commands::sync::manifest_for(&send_session, &catalog_rx.borrow_and_update());
+            if sender
+                .send(Message::Text(manifest.to_text().into()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+
+            loop {
+                tokio::select! {
+                    // Checked in order, so asset chunks only go out when nothing else waits
+                    biased;
+                    // Broadcast messages, for everyone or for a room we are in
+                    Ok(msg) = rx.recv() => {
+                        if !send_state.hears(&send_session.client_id, &msg) {
+                            continue;
+                        }
+                        if sender.send(Message::Text(msg.text.into())).await.is_err() {
+                            break;
+                        }
                     }
-                }
-                // Local unicast messages (e.g. file transfers)
-                Some(msg) = local_rx.recv() => {
-                    if sender.send(Message::Text(msg.into())).await.is_err() {
+                    // Our token was revoked, hang up
+                    Ok(name) = revocations.recv() => {
+                        if send_session.token_name.as_deref() == Some(name.as_str()) {
+                            tracing::warn!("Token {} revoked, closing connection", name);
+                            let _ = sender.send(Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "token revoked".into(),
+                            }))).await;
+                            break;
+                        }
+                    }
+                    // The same identity connected again, this one is stale
+                    () = superseded.notified() => {
+                        let _ = sender.send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "connected elsewhere".into(),
+                        }))).await;
                         break;
                     }
+                    // Assets changed on disk, let the client catch up
+                    Ok(()) = catalog_rx.changed() => {
+                        let catalog = catalog_rx.borrow_and_update().clone();
+                        let manifest = commands::sync::manifest_for(&send_session, &catalog);
+                        if sender.send(Message::Text(manifest.to_text().into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Local unicast messages
+                    Some(msg) = local_rx.recv() => {
+                        if sender.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Binary file transfers
+                    Some(msg) = bulk_rx.recv() => {
+                        if sender.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
                 }
             }
         }
-    });
+        .instrument(span.clone()),
+    );
 
     tokio::select! {
         _ = (&mut recv_task) => send_task.abort(),
         _ = (&mut send_task) => recv_task.abort(),
     };
+    span.in_scope(|| tracing::info!("Client disconnected"));
 }
@@ -0,0 +1,270 @@
+use crate::config::TokenConfig;
+use crate::identity::IdentityKey;
+use crate::state::{AppState, Link};
+use axum::extract::ws::{Message, WebSocket};
+use common::{Hello, Status, Welcome, WsMessage, PROTOCOL_VERSION};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long a freshly connected client has to send its `hello`.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Optional features this server can use when the client supports them too.
+const SUPPORTED_FEATURES: &[&str] = &[];
+
+/// Audio formats the server is able to serve.
+const SERVED_FORMATS: &[&str] = &["mp3", "wav"];
+
+const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+/// What was agreed on with a client during the handshake.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Server-assigned identity, the only one cooldowns and logs trust.
+    pub client_id: String,
+    pub display_name: String,
+    /// Name of the token the client authenticated with, if auth is enabled.
+    pub token_name: Option<String>,
+    /// Whether that token is an admin one, allowed to cancel anyone's rings.
+    pub admin: bool,
+    pub client_version: String,
+    /// Rooms subscribed to during the handshake; later changes live with the connection.
+    pub rooms: BTreeSet<String>,
+    /// Status announced in the hello; later changes live in the connection registry.
+    pub status: Status,
+    pub audio_formats: Vec<String>,
+    pub features: Vec<String>,
+}
+
+impl Session {
+    pub fn accepts_file(&self, filename: &str) -> bool {
+        filename
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| self.audio_formats.iter().any(|f| f == ext))
+    }
+}
+
+/// Holds a session's identity, registered in [`AppState::connections`], for as long as the
+/// connection lives, however it ends.
+pub struct Claim {
+    state: Arc<AppState>,
+    client_id: String,
+    serial: u64,
+}
+
+impl Drop for Claim {
+    /// Frees the identity and forgets its connection once it is gone.
+    fn drop(&mut self) {
+        self.state.unregister(&self.client_id, self.serial);
+    }
+}
+
+/// Waits for the client's `hello` and answers with `welcome` or `rejected`.
+///
+/// On rejection the reason has already been sent to the client; the caller only has to drop the socket.
+/// On success the session is registered with `link` to reach it, and its identity is held by
+/// the returned [`Claim`] until it is dropped.
+pub async fn negotiate(
+    socket: &mut WebSocket,
+    state: &Arc<AppState>,
+    token: Option<TokenConfig>,
+    link: Link,
+) -> Result<(Session, Claim), String> {
+    let result = match tokio::time::timeout(HELLO_TIMEOUT, socket.recv()).await {
+        Ok(Some(Ok(Message::Text(text)))) => match WsMessage::from_text(&text) {
+            Ok(WsMessage::Hello(hello)) => check_hello(hello, state, token, link),
+            Ok(_) => Err("expected a hello message first, please upgrade your client".to_string()),
+            Err(e) => Err(format!("invalid hello: {}", e)),
+        },
+        Ok(Some(Ok(_))) => Err("expected a hello text message".to_string()),
+        Ok(Some(Err(e))) => Err(format!("connection error: {}", e)),
+        Ok(None) => Err("connection closed before hello".to_string()),
+        Err(_) => Err("timed out waiting for hello".to_string()),
+    };
+
+    let reply = match &result {
+        Ok((session, _)) => WsMessage::Welcome(Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            client_id: session.client_id.clone(),
+            client_secret: state.identity_key.secret(&session.client_id),
+            rooms: session.rooms.iter().cloned().collect(),
+            features: session.features.clone(),
+        }),
+        Err(reason) => WsMessage::rejected(reason.clone()),
+    };
+    if socket
+        .send(Message::Text(reply.to_text().into()))
+        .await
+        .is_err()
+    {
+        return Err("connection closed during handshake".to_string());
+    }
+
+    if result.is_err() {
+        let _ = socket.send(Message::Close(None)).await;
+    }
+    result
+}
+
+fn check_hello(
+    hello: Hello,
+    state: &Arc<AppState>,
+    token: Option<TokenConfig>,
+    link: Link,
+) -> Result<(Session, Claim), String> {
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(format!(
+            "protocol version {} is not supported (server speaks {}), please upgrade your client",
+            hello.protocol_version, PROTOCOL_VERSION
+        ));
+    }
+
+    let audio_formats: Vec<String> = hello
+        .audio_formats
+        .into_iter()
+        .map(|f| f.to_ascii_lowercase())
+        .filter(|f| SERVED_FORMATS.contains(&f.as_str()))
+        .collect();
+    if audio_formats.is_empty() {
+        return Err(format!(
+            "no common audio format (server serves {})",
+            SERVED_FORMATS.join(", ")
+        ));
+    }
+
+    let features = hello
+        .features
+        .into_iter()
+        .filter(|f| SUPPORTED_FEATURES.contains(&f.as_str()))
+        .collect();
+
+    // A per-client token decides the identity, whatever the client asks for.
+    let bound = token.as_ref().and_then(|token| token.client_id.clone());
+    let requested = bound.clone().or_else(|| {
+        proven_identity(
+            hello.client_id.as_deref(),
+            hello.client_secret.as_deref(),
+            &state.identity_key,
+        )
+    });
+    let mut session = Session {
+        client_id: requested
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string()),
+        display_name: String::new(),
+        admin: token.as_ref().is_some_and(|token| token.admin),
+        token_name: token.map(|token| token.name),
+        client_version: hello.client_version,
+        rooms: state.rooms.existing(&hello.rooms),
+        status: hello.status,
+        audio_formats,
+        features,
+    };
+    // A client that proved its identity gets it back even if a connection still holds it: that
+    // one is most likely a stale socket, left behind by sleep or a network switch.
+    let take_over = requested.is_some();
+    let serial = loop {
+        session.display_name =
+            clean_display_name(hello.display_name.as_deref(), &session.client_id);
+        if let Some(serial) = state.register(&session, link.clone(), take_over) {
+            break serial;
+        }
+        session.client_id = Uuid::new_v4().to_string();
+    };
+    let claim = Claim {
+        state: state.clone(),
+        client_id: session.client_id.clone(),
+        serial,
+    };
+    Ok((session, claim))
+}
+
+/// The requested identity, if the secret shows we issued it.
+fn proven_identity(
+    requested: Option<&str>,
+    secret: Option<&str>,
+    key: &IdentityKey,
+) -> Option<String> {
+    let id = Uuid::parse_str(requested?).ok()?.to_string();
+    if key.verify(&id, secret.unwrap_or_default()) {
+        Some(id)
+    } else {
+        tracing::warn!("Identity {} was not proven, assigning a new one", id);
+        None
+    }
+}
+
+fn clean_display_name(requested: Option<&str>, client_id: &str) -> String {
+    let name: String = requested
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_DISPLAY_NAME_LEN)
+        .collect();
+    if name.is_empty() {
+        format!("Client {}", client_id.chars().take(8).collect::<String>())
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "6f1c1a52-4a8e-4b8a-9a57-0d7f3e0b2c11";
+
+    #[test]
+    fn fallback_display_name_copes_with_short_ids() {
+        assert_eq!(clean_display_name(None, ID), "Client 6f1c1a52");
+        assert_eq!(clean_display_name(Some(" \n "), "dé"), "Client dé");
+        assert_eq!(clean_display_name(Some(" Alice\u{7} "), ID), "Alice");
+    }
+
+    #[test]
+    fn identity_is_kept_with_its_secret() {
+        let key = IdentityKey::from_bytes(&[1; 32]);
+        let secret = key.secret(ID);
+
+        assert_eq!(
+            proven_identity(Some(ID), Some(&secret), &key).as_deref(),
+            Some(ID)
+        );
+        assert_eq!(
+            proven_identity(Some(&ID.to_uppercase()), Some(&secret), &key).as_deref(),
+            Some(ID)
+        );
+    }
+
+    #[test]
+    fn unproven_identities_are_refused() {
+        let key = IdentityKey::from_bytes(&[1; 32]);
+        let other = Uuid::new_v4().to_string();
+
+        assert_eq!(proven_identity(Some(ID), None, &key), None);
+        assert_eq!(proven_identity(Some(ID), Some(""), &key), None);
+        assert_eq!(
+            proven_identity(Some(ID), Some(&key.secret(&other)), &key),
+            None
+        );
+        assert_eq!(
+            proven_identity(
+                Some(ID),
+                Some(&IdentityKey::from_bytes(&[2; 32]).secret(ID)),
+                &key
+            ),
+            None
+        );
+        assert_eq!(
+            proven_identity(Some("alice"), Some(&key.secret("alice")), &key),
+            None
+        );
+        assert_eq!(proven_identity(None, Some(&key.secret(ID)), &key), None);
+    }
+}
@@ -0,0 +1,370 @@
+use chrono::{DateTime, Utc};
+use common::{RingKind, RingTarget};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// One delivered ring, as stored in the history file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RingRecord {
+    pub id: String,
+    pub at: DateTime<Utc>,
+    pub room: String,
+    pub sender_id: String,
+    pub sender_name: String,
+    #[serde(default)]
+    pub kind: RingKind,
+    #[serde(default)]
+    pub sound_hash: Option<String>,
+    #[serde(default)]
+    pub target: Option<RingTarget>,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Connections the ring was sent to.
+    pub listeners: usize,
+    /// Display names of whoever answered the ring, first one first.
+    #[serde(default)]
+    pub acknowledged_by: Vec<String>,
+    /// Display name of whoever cancelled the ring.
+    #[serde(default)]
+    pub cancelled_by: Option<String>,
+}
+
+/// Line appended when someone answers a ring, applied to it on load.
+#[derive(Serialize, Deserialize, Debug)]
+struct AckRecord {
+    ack: String,
+    by: String,
+    at: DateTime<Utc>,
+}
+
+/// Line appended when a ring is cancelled, applied to it on load.
+#[derive(Serialize, Deserialize, Debug)]
+struct CancelRecord {
+    cancel: String,
+    by: String,
+    at: DateTime<Utc>,
+}
+
+/// What the history file holds: rings, and later answers to or cancellations of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Ring(RingRecord),
+    Ack(AckRecord),
+    Cancel(CancelRecord),
+}
+
+/// Criteria for [`History::query`]; unset fields match everything.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct HistoryFilter {
+    pub room: Option<String>,
+    /// Client ID or display name of the ringer.
+    pub sender: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Keeps only the most recent matches.
+    pub limit: Option<usize>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, record: &RingRecord) -> bool {
+        self.room.as_ref().is_none_or(|room| &record.room == room)
+            && self.sender.as_ref().is_none_or(|sender| {
+                &record.sender_id == sender || record.sender_name.eq_ignore_ascii_case(sender)
+            })
+            && self.since.is_none_or(|since| record.at >= since)
+            && self.until.is_none_or(|until| record.at < until)
+    }
+}
+
+/// Every ring ever delivered, appended to a JSON Lines file and kept in memory for queries.
+pub struct History {
+    file: Mutex<File>,
+    records: Mutex<Vec<RingRecord>>,
+}
+
+impl History {
+    /// Loads the history file, creating it if needed. Unreadable lines are skipped.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mut records = Vec::new();
+        if path.exists() {
+            for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(Line::Ring(record)) => records.push(record),
+                    Ok(Line::Ack(ack)) => apply_ack(&mut records, &ack.ack, &ack.by),
+                    Ok(Line::Cancel(cancel)) => {
+                        apply_cancel(&mut records, &cancel.cancel, &cancel.by)
+                    }
+                    Err(e) => {
+                        tracing::warn!("Skipping line {} of {}: {}", number + 1, path.display(), e)
+                    }
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            records: Mutex::new(records),
+        })
+    }
+
+    pub fn record(&self, record: RingRecord) {
+        self.append(&serde_json::to_string(&record).expect("RingRecord is always serializable"));
+        self.records.lock().unwrap().push(record);
+    }
+
+    /// Notes that `by` answered ring `ring_id`.
+    pub fn acknowledge(&self, ring_id: &str, by: &str) {
+        let ack = AckRecord {
+            ack: ring_id.to_string(),
+            by: by.to_string(),
+            at: Utc::now(),
+        };
+        self.append(&serde_json::to_string(&ack).expect("AckRecord is always serializable"));
+        apply_ack(&mut self.records.lock().unwrap(), ring_id, by);
+    }
+
+    /// Notes that `by` cancelled ring `ring_id`.
+    pub fn cancel(&self, ring_id: &str, by: &str) {
+        let cancel = CancelRecord {
+            cancel: ring_id.to_string(),
+            by: by.to_string(),
+            at: Utc::now(),
+        };
+        self.append(&serde_json::to_string(&cancel).expect("CancelRecord is always serializable"));
+        apply_cancel(&mut self.records.lock().unwrap(), ring_id, by);
+    }
+
+    fn append(&self, line: &str) {
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            tracing::error!("Failed to write ring history: {}", e);
+        }
+    }
+
+    /// Matching rings, most recent first.
+    pub fn query(&self, filter: &HistoryFilter) -> Vec<RingRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|record| filter.matches(record))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    /// The last `count` rings broadcast to any of `rooms`, most recent first.
+    ///
+    /// Targeted rings are left out, they were none of the room's business.
+    pub fn recent(&self, rooms: &BTreeSet<String>, count: usize) -> Vec<RingRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|record| record.target.is_none() && rooms.contains(&record.room))
+            .take(count)
+            .cloned()
+            .collect()
+    }
+}
+
+fn apply_ack(records: &mut [RingRecord], ring_id: &str, by: &str) {
+    // Answers come shortly after their ring, so search from the end.
+    if let Some(record) = records.iter_mut().rev().find(|record| record.id == ring_id) {
+        if !record.acknowledged_by.iter().any(|name| name == by) {
+            record.acknowledged_by.push(by.to_string());
+        }
+    }
+}
+
+fn apply_cancel(records: &mut [RingRecord], ring_id: &str, by: &str) {
+    if let Some(record) = records.iter_mut().rev().find(|record| record.id == ring_id) {
+        record.cancelled_by = Some(by.to_string());
+    }
+}
+
+/// Renders records as CSV with a header line.
+pub fn to_csv(records: &[RingRecord]) -> String {
+    let mut csv = String::from(
+        "id,at,room,kind,sender_id,sender_name,target,message,sound_hash,listeners,acknowledged_by,cancelled_by\n",
+    );
+    for record in records {
+        let target = match &record.target {
+            Some(RingTarget::Person(who)) => format!("person:{}", who),
+            Some(RingTarget::Group(name)) => format!("group:{}", name),
+            None => String::new(),
+        };
+        let fields = [
+            record.id.clone(),
+            record.at.to_rfc3339(),
+            record.room.clone(),
+            record.kind.name().to_string(),
+            record.sender_id.clone(),
+            record.sender_name.clone(),
+            target,
+            record.message.clone().unwrap_or_default(),
+            record.sound_hash.clone().unwrap_or_default(),
+            record.listeners.to_string(),
+            record.acknowledged_by.join(";"),
+            record.cancelled_by.clone().unwrap_or_default(),
+        ];
+        let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&line.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn record(id: &str, room: &str, sender: &str, hour: u32) -> RingRecord {
+        RingRecord {
+            id: id.to_string(),
+            at: Utc.with_ymd_and_hms(2026, 3, 2, hour, 0, 0).unwrap(),
+            room: room.to_string(),
+            sender_id: format!("id-{}", sender.to_lowercase()),
+            sender_name: sender.to_string(),
+            kind: RingKind::default(),
+            sound_hash: None,
+            target: None,
+            message: None,
+            listeners: 1,
+            acknowledged_by: Vec::new(),
+            cancelled_by: None,
+        }
+    }
+
+    fn at(hour: u32) -> Option<DateTime<Utc>> {
+        Some(Utc.with_ymd_and_hms(2026, 3, 2, hour, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(HistoryFilter::default().matches(&record("r1", "general", "Alice", 9)));
+    }
+
+    #[test]
+    fn filter_by_room_and_sender() {
+        let ring = record("r1", "general", "Alice", 9);
+        let filter = |room: Option<&str>, sender: Option<&str>| HistoryFilter {
+            room: room.map(str::to_string),
+            sender: sender.map(str::to_string),
+            ..HistoryFilter::default()
+        };
+
+        assert!(filter(Some("general"), None).matches(&ring));
+        assert!(!filter(Some("reception"), None).matches(&ring));
+        assert!(filter(None, Some("id-alice")).matches(&ring));
+        assert!(filter(None, Some("ALICE")).matches(&ring));
+        assert!(!filter(None, Some("Bob")).matches(&ring));
+        assert!(!filter(Some("reception"), Some("Alice")).matches(&ring));
+    }
+
+    #[test]
+    fn since_is_inclusive_and_until_exclusive() {
+        let ring = record("r1", "general", "Alice", 9);
+        let filter = |since, until| HistoryFilter {
+            since,
+            until,
+            ..HistoryFilter::default()
+        };
+
+        assert!(filter(at(9), None).matches(&ring));
+        assert!(!filter(at(10), None).matches(&ring));
+        assert!(filter(None, at(10)).matches(&ring));
+        assert!(!filter(None, at(9)).matches(&ring));
+        assert!(filter(at(8), at(10)).matches(&ring));
+    }
+
+    #[test]
+    fn query_returns_latest_matches_first_and_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("history-{}.jsonl", uuid::Uuid::new_v4()));
+        let history = History::open(&path).unwrap();
+        history.record(record("r1", "general", "Alice", 8));
+        history.record(record("r2", "reception", "Bob", 9));
+        history.record(record("r3", "general", "Bob", 10));
+        history.acknowledge("r1", "Carol");
+        history.cancel("r3", "Bob");
+
+        let reopened = History::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let ids = |filter: &HistoryFilter| -> Vec<String> {
+            reopened
+                .query(filter)
+                .into_iter()
+                .map(|record| record.id)
+                .collect()
+        };
+
+        assert_eq!(ids(&HistoryFilter::default()), ["r3", "r2", "r1"]);
+        assert_eq!(
+            ids(&HistoryFilter {
+                limit: Some(2),
+                ..HistoryFilter::default()
+            }),
+            ["r3", "r2"]
+        );
+        assert_eq!(
+            ids(&HistoryFilter {
+                room: Some("general".to_string()),
+                limit: Some(1),
+                ..HistoryFilter::default()
+            }),
+            ["r3"]
+        );
+        let all = reopened.query(&HistoryFilter::default());
+        assert_eq!(all[2].acknowledged_by, ["Carol"]);
+        assert_eq!(all[0].cancelled_by.as_deref(), Some("Bob"));
+    }
+
+    #[test]
+    fn csv_quotes_fields_with_separators_quotes_and_newlines() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn csv_has_a_header_and_one_line_per_ring() {
+        let mut ring = record("r1", "general", "Alice", 9);
+        ring.sender_name = "Alice, \"A\"".to_string();
+        ring.target = Some(RingTarget::Group("ops".to_string()));
+        ring.message = Some("colis".to_string());
+        ring.acknowledged_by = vec!["Bob".to_string(), "Carol".to_string()];
+
+        let csv = to_csv(&[ring]);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("id,at,room,kind,sender_id,sender_name,"));
+        assert_eq!(
+            lines[1],
+            "r1,2026-03-02T09:00:00+00:00,general,doorbell,id-alice,\"Alice, \"\"A\"\"\",group:ops,colis,,1,Bob;Carol,"
+        );
+    }
+}
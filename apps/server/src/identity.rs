@@ -0,0 +1,111 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+const KEY_LEN: usize = 32;
+
+/// Vouches for the identities this server hands out.
+///
+/// Each identity comes with a secret, an HMAC of the client ID under a key only the server
+/// knows. A returning client gets its identity back only by presenting that secret, so knowing
+/// someone's client ID isn't enough to take their place.
+pub struct IdentityKey {
+    key: Vec<u8>,
+}
+
+impl IdentityKey {
+    /// Reads the key from `path`, generating it on first start.
+    ///
+    /// Losing the file gives every client a new identity on its next connection.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let key = hex::decode(text.trim())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if key.len() < KEY_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("key must be at least {} bytes", KEY_LEN),
+                    ));
+                }
+                Ok(Self { key })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = rand::random::<[u8; KEY_LEN]>().to_vec();
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                writeln!(options.open(path)?, "{}", hex::encode(&key))?;
+                tracing::info!("Generated a new identity key in {}", path.display());
+                Ok(Self { key })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_bytes(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    /// The secret proving ownership of `client_id`.
+    pub fn secret(&self, client_id: &str) -> String {
+        hex::encode(self.mac(client_id).finalize().into_bytes())
+    }
+
+    /// Whether `secret` was issued by this server for `client_id`.
+    pub fn verify(&self, client_id: &str, secret: &str) -> bool {
+        hex::decode(secret).is_ok_and(|secret| self.mac(client_id).verify_slice(&secret).is_ok())
+    }
+
+    fn mac(&self, client_id: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(client_id.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_verify_only_for_their_identity_and_key() {
+        let key = IdentityKey::from_bytes(&[7; KEY_LEN]);
+        let secret = key.secret("alice");
+
+        assert!(key.verify("alice", &secret));
+        assert!(!key.verify("bob", &secret));
+        assert!(!key.verify("alice", &key.secret("bob")));
+        assert!(!key.verify("alice", ""));
+        assert!(!key.verify("alice", "not hex"));
+        assert!(!IdentityKey::from_bytes(&[8; KEY_LEN]).verify("alice", &secret));
+    }
+
+    #[test]
+    fn key_is_generated_once_then_reused() {
+        let path = std::env::temp_dir().join(format!("identity-{}.key", uuid::Uuid::new_v4()));
+        let first = IdentityKey::open(&path).unwrap();
+        let second = IdentityKey::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(first.secret("alice"), second.secret("alice"));
+    }
+
+    #[test]
+    fn short_or_garbled_keys_are_refused() {
+        let path = std::env::temp_dir().join(format!("identity-{}.key", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "abcd").unwrap();
+        let short = IdentityKey::open(&path);
+        std::fs::write(&path, "zz").unwrap();
+        let garbled = IdentityKey::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(short.is_err());
+        assert!(garbled.is_err());
+    }
+}
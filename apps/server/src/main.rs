@@ -1,11 +1,26 @@
+mod api;
+mod auth;
+mod catalog;
 mod commands;
+mod config;
+mod escalation;
 mod handler;
+mod handshake;
+mod history;
+mod identity;
+mod rings;
+mod rooms;
 mod state;
 mod sync;
+mod webhooks;
 
 use axum::routing::get;
 use axum::Router;
+use catalog::Catalog;
+use config::Config;
 use handler::ws_handler;
+use history::History;
+use identity::IdentityKey;
 use state::AppState;
 use std::net::SocketAddr;
 
@@ -15,10 +30,50 @@ async fn main() {
         .with_env_filter("server=debug,tower_http=debug")
         .init();
 
-    let state = AppState::new();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Failed to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let catalog = catalog::scan(&Catalog::default())
+        .await
+        .expect("Failed to scan assets directory");
+    tracing::info!("Publishing {} assets", catalog.assets().len());
+    let history = match History::open(&config.history.path) {
+        Ok(history) => history,
+        Err(e) => {
+            tracing::error!(
+                "Failed to open ring history {}: {}",
+                config.history.path.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    let identity_key = match IdentityKey::open(&config.identity.key_path) {
+        Ok(key) => key,
+        Err(e) => {
+            tracing::error!(
+                "Failed to open identity key {}: {}",
+                config.identity.key_path.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    let state = AppState::new(config, catalog, history, identity_key);
+    if !state.auth.is_enabled() {
+        tracing::warn!("No tokens configured, anyone who can reach the server may connect");
+    }
+    let _watcher = catalog::watch(state.clone())
+        .inspect_err(|e| tracing::warn!("Asset changes won't be picked up at runtime: {}", e));
 
     let app = Router::new()
         .route("/ws", get(ws_handler))
+        .nest("/api", api::router())
         .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
@@ -0,0 +1,92 @@
+use common::{RingKind, RingTarget};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a ring can still be answered.
+const ANSWER_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// A delivered ring that can still be answered.
+#[derive(Debug, Clone)]
+pub struct ActiveRing {
+    pub room: String,
+    pub sender_id: String,
+    pub sender_name: String,
+    pub kind: RingKind,
+    /// Who the latest attempt was for; the whole room if unset.
+    pub target: Option<RingTarget>,
+    pub available_only: bool,
+    pub sound_hash: Option<String>,
+    pub message: Option<String>,
+    /// Re-rings so far.
+    pub attempt: u32,
+    /// Identities the ring was sent to, not counting the sender.
+    pub recipients: BTreeSet<String>,
+    /// Display names of whoever answered, first one first.
+    pub acknowledged_by: Vec<String>,
+}
+
+/// Rings still within their answer window, by ring ID.
+#[derive(Default)]
+pub struct ActiveRings {
+    rings: Mutex<HashMap<String, (Instant, ActiveRing)>>,
+}
+
+impl ActiveRings {
+    pub fn start(&self, ring_id: &str, ring: ActiveRing) {
+        let mut rings = self.rings.lock().unwrap();
+        rings.retain(|_, (started, _)| started.elapsed() < ANSWER_WINDOW);
+        rings.insert(ring_id.to_string(), (Instant::now(), ring));
+    }
+
+    /// The ring, if it can still be answered.
+    pub fn get(&self, ring_id: &str) -> Option<ActiveRing> {
+        let rings = self.rings.lock().unwrap();
+        let (started, ring) = rings.get(ring_id)?;
+        (started.elapsed() < ANSWER_WINDOW).then(|| ring.clone())
+    }
+
+    /// Ends the ring early: it can no longer be answered nor escalated.
+    pub fn remove(&self, ring_id: &str) -> Option<ActiveRing> {
+        self.rings
+            .lock()
+            .unwrap()
+            .remove(ring_id)
+            .map(|(_, ring)| ring)
+    }
+
+    /// The ring, if it is still waiting for an answer.
+    pub fn unanswered(&self, ring_id: &str) -> Option<ActiveRing> {
+        let rings = self.rings.lock().unwrap();
+        let (started, ring) = rings.get(ring_id)?;
+        (started.elapsed() < ANSWER_WINDOW && ring.acknowledged_by.is_empty()).then(|| ring.clone())
+    }
+
+    /// Replaces the ring's state after a re-ring, keeping its answer window.
+    pub fn update(&self, ring_id: &str, ring: ActiveRing) {
+        if let Some((_, current)) = self.rings.lock().unwrap().get_mut(ring_id) {
+            *current = ring;
+        }
+    }
+
+    /// Records that `client_id` answered the ring, returning it as updated.
+    pub fn acknowledge(
+        &self,
+        ring_id: &str,
+        client_id: &str,
+        display_name: &str,
+    ) -> Result<ActiveRing, &'static str> {
+        let mut rings = self.rings.lock().unwrap();
+        let (started, ring) = rings.get_mut(ring_id).ok_or("unknown ring")?;
+        if started.elapsed() >= ANSWER_WINDOW {
+            return Err("ring is too old to answer");
+        }
+        if !ring.recipients.contains(client_id) {
+            return Err("ring was not sent to this client");
+        }
+        if !ring.acknowledged_by.iter().any(|name| name == display_name) {
+            ring.acknowledged_by.push(display_name.to_string());
+        }
+        Ok(ring.clone())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::config::RoomConfig;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Named doorbells hosted by this server, editable at runtime through the admin API.
+pub struct Rooms {
+    rooms: Mutex<BTreeMap<String, RoomConfig>>,
+    /// Room used when a client doesn't say which one it means.
+    default: String,
+}
+
+impl Rooms {
+    pub fn new(configs: Vec<RoomConfig>) -> Self {
+        let configs = if configs.is_empty() {
+            vec![RoomConfig::named("general")]
+        } else {
+            configs
+        };
+        Self {
+            default: configs[0].name.clone(),
+            rooms: Mutex::new(
+                configs
+                    .into_iter()
+                    .map(|room| (room.name.clone(), room))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<RoomConfig> {
+        self.rooms.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<RoomConfig> {
+        self.rooms.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The room a ring goes to: the requested one, or the default if none was given.
+    ///
+    /// Falls back to any remaining room if the default has been deleted.
+    pub fn resolve(&self, requested: Option<&str>) -> Option<RoomConfig> {
+        let rooms = self.rooms.lock().unwrap();
+        match requested {
+            Some(name) => rooms.get(name).cloned(),
+            None => rooms
+                .get(&self.default)
+                .or_else(|| rooms.values().next())
+                .cloned(),
+        }
+    }
+
+    /// Keeps the names of rooms that exist; an empty request means the default room.
+    pub fn existing(&self, requested: &[String]) -> BTreeSet<String> {
+        if requested.is_empty() {
+            return self
+                .resolve(None)
+                .map(|room| room.name)
+                .into_iter()
+                .collect();
+        }
+        let rooms = self.rooms.lock().unwrap();
+        requested
+            .iter()
+            .filter(|name| rooms.contains_key(*name))
+            .cloned()
+            .collect()
+    }
+
+    pub fn upsert(&self, room: RoomConfig) -> Result<(), String> {
+        validate_name(&room.name)?;
+        self.rooms.lock().unwrap().insert(room.name.clone(), room);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.rooms.lock().unwrap().remove(name).is_some()
+    }
+}
+
+/// Checks configured rooms the way the admin API checks new ones, and that no name repeats.
+pub fn validate(configs: &[RoomConfig]) -> Result<(), String> {
+    let mut names = BTreeSet::new();
+    for room in configs {
+        validate_name(&room.name).map_err(|e| format!("room {:?}: {}", room.name, e))?;
+        if !names.insert(room.name.as_str()) {
+            return Err(format!("room {:?} is configured twice", room.name));
+        }
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LEN {
+        return Err(format!(
+            "room names must be 1 to {} characters",
+            MAX_ROOM_NAME_LEN
+        ));
+    }
+    if name.chars().any(|c| c.is_control() || c == '/') {
+        return Err("room names must not contain control characters or '/'".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_name_accepts_ordinary_names() {
+        assert!(validate_name("reception").is_ok());
+        assert!(validate_name("salle de pause").is_ok());
+        assert!(validate_name(&"é".repeat(MAX_ROOM_NAME_LEN)).is_ok());
+    }
+
+    #[test]
+    fn validate_name_refuses_empty_long_and_unsafe_names() {
+        assert!(validate_name("").is_err());
+        assert!(validate_name(&"a".repeat(MAX_ROOM_NAME_LEN + 1)).is_err());
+        assert!(validate_name("a/b").is_err());
+        assert!(validate_name("line\nbreak").is_err());
+        assert!(validate_name("tab\t").is_err());
+    }
+
+    #[test]
+    fn validate_refuses_bad_or_repeated_configured_rooms() {
+        let rooms = |names: &[&str]| -> Vec<RoomConfig> {
+            names.iter().map(|name| RoomConfig::named(name)).collect()
+        };
+
+        assert!(validate(&[]).is_ok());
+        assert!(validate(&rooms(&["general", "reception"])).is_ok());
+        assert!(validate(&rooms(&["general", "a/b"])).is_err());
+        assert!(validate(&rooms(&["general", "reception", "general"])).is_err());
+    }
+
+    #[test]
+    fn upsert_keeps_invalid_rooms_out() {
+        let rooms = Rooms::new(Vec::new());
+
+        assert!(rooms.upsert(RoomConfig::named("../etc")).is_err());
+        assert!(rooms.upsert(RoomConfig::named("reception")).is_ok());
+        let names: Vec<String> = rooms.list().into_iter().map(|room| room.name).collect();
+        assert_eq!(names, ["general", "reception"]);
+    }
+}
@@ -1,19 +1,397 @@
-use std::collections::HashMap;
+use crate::auth::Auth;
+use crate::catalog::Catalog;
+use crate::config::{Config, EscalationConfig, GroupConfig, KindConfig};
+use crate::handshake::Session;
+use crate::history::History;
+use crate::identity::IdentityKey;
+use crate::rings::ActiveRings;
+use crate::rooms::Rooms;
+use crate::webhooks::Webhooks;
+use axum::extract::ws::Message;
+use common::{PersonPresence, RingKind, Status, WsMessage};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
-use tokio::sync::broadcast;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+
+/// A message for every connection, or only for those subscribed to `room`.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub room: Option<String>,
+    /// Skips connections whose status isn't [`Status::Available`].
+    pub available_only: bool,
+    pub text: String,
+}
+
+impl Broadcast {
+    pub fn everyone(msg: &WsMessage) -> Self {
+        Self {
+            room: None,
+            available_only: false,
+            text: msg.to_text(),
+        }
+    }
+}
+
+/// Way to reach one live connection's tasks.
+#[derive(Debug, Clone)]
+pub struct Link {
+    /// Unicast control messages for the client; asset chunks travel apart so they never crowd
+    /// these out.
+    pub sender: mpsc::Sender<Message>,
+    /// Woken when another connection takes this one's identity over, to hang up.
+    pub superseded: Arc<Notify>,
+}
+
+impl Link {
+    pub fn new(sender: mpsc::Sender<Message>) -> Self {
+        Self {
+            sender,
+            superseded: Arc::new(Notify::new()),
+        }
+    }
+}
+
+/// Way to reach one live connection directly.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub display_name: String,
+    pub status: Status,
+    /// Rooms whose rings this connection hears.
+    pub rooms: BTreeSet<String>,
+    pub link: Link,
+    /// Tells this connection apart from later ones with the same identity.
+    serial: u64,
+}
+
+impl Connection {
+    /// Whether a broadcast is meant for this connection.
+    pub fn hears(&self, room: Option<&str>, available_only: bool) -> bool {
+        let in_room = room.is_none_or(|room| self.rooms.contains(room));
+        in_room && (!available_only || self.status == Status::Available)
+    }
+}
 
 pub struct AppState {
-    pub tx: broadcast::Sender<String>,
-    pub last_trigger: Mutex<HashMap<String, Instant>>,
+    pub tx: broadcast::Sender<Broadcast>,
+    /// Last ring per (room, kind, client identity), for cooldowns.
+    pub last_trigger: Mutex<HashMap<(String, RingKind, String), Instant>>,
+    /// Live connections by identity, registered during the handshake; an identity is online while
+    /// it is in there.
+    pub connections: Mutex<HashMap<String, Connection>>,
+    next_serial: AtomicU64,
+    /// Published assets; connections subscribe to push manifest updates.
+    pub catalog: watch::Sender<Arc<Catalog>>,
+    pub auth: Auth,
+    pub rooms: Rooms,
+    pub kinds: HashMap<RingKind, KindConfig>,
+    pub groups: Vec<GroupConfig>,
+    pub webhooks: Webhooks,
+    pub history: History,
+    pub identity_key: IdentityKey,
+    pub active_rings: ActiveRings,
+    pub escalation: EscalationConfig,
 }
 
 impl AppState {
-    pub fn new() -> Arc<Self> {
+    pub fn new(
+        config: Config,
+        catalog: Catalog,
+        history: History,
+        identity_key: IdentityKey,
+    ) -> Arc<Self> {
         let (tx, _rx) = broadcast::channel(100);
         Arc::new(Self {
             tx,
             last_trigger: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            next_serial: AtomicU64::new(0),
+            catalog: watch::Sender::new(Arc::new(catalog)),
+            auth: Auth::new(config.auth),
+            rooms: Rooms::new(config.rooms),
+            kinds: config.kinds,
+            groups: config.groups,
+            webhooks: Webhooks::new(config.webhooks),
+            history,
+            identity_key,
+            active_rings: ActiveRings::default(),
+            escalation: config.escalation,
         })
     }
+
+    /// Makes the session reachable through [`AppState::connections`] and tells everyone it joined.
+    ///
+    /// Another connection holding the identity keeps it, and `None` is returned, unless
+    /// `take_over` is set: that one is then told to hang up. Returns the serial to
+    /// [`unregister`](Self::unregister) with.
+    pub fn register(&self, session: &Session, link: Link, take_over: bool) -> Option<u64> {
+        let connection = Connection {
+            display_name: session.display_name.clone(),
+            status: session.status,
+            rooms: session.rooms.clone(),
+            link,
+            serial: self.next_serial.fetch_add(1, Ordering::Relaxed),
+        };
+        let serial = connection.serial;
+        let joined = WsMessage::Joined(presence_of(&session.client_id, &connection));
+        match self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(session.client_id.clone())
+        {
+            Entry::Occupied(mut entry) if take_over => {
+                let previous = entry.insert(connection);
+                tracing::info!(
+                    "Identity {} connected again, closing its previous connection",
+                    session.client_id
+                );
+                previous.link.superseded.notify_one();
+            }
+            Entry::Occupied(_) => return None,
+            Entry::Vacant(entry) => {
+                entry.insert(connection);
+            }
+        };
+        let _ = self.tx.send(Broadcast::everyone(&joined));
+        Some(serial)
+    }
+
+    /// Forgets a connection and tells everyone it left, unless its identity was taken over.
+    pub fn unregister(&self, client_id: &str, serial: u64) {
+        let removed = match self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(client_id.to_string())
+        {
+            Entry::Occupied(entry) if entry.get().serial == serial => Some(entry.remove()),
+            _ => None,
+        };
+        if let Some(connection) = removed {
+            let left = WsMessage::Left(presence_of(client_id, &connection));
+            let _ = self.tx.send(Broadcast::everyone(&left));
+        }
+    }
+
+    pub fn set_status(&self, client_id: &str, status: Status) {
+        let changed = {
+            let mut connections = self.connections.lock().unwrap();
+            match connections.get_mut(client_id) {
+                Some(connection) if connection.status != status => {
+                    connection.status = status;
+                    Some(presence_of(client_id, connection))
+                }
+                _ => None,
+            }
+        };
+        if let Some(presence) = changed {
+            let _ = self
+                .tx
+                .send(Broadcast::everyone(&WsMessage::StatusChanged(presence)));
+        }
+    }
+
+    pub fn is_available(&self, client_id: &str) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .is_some_and(|connection| connection.status == Status::Available)
+    }
+
+    /// Replaces the rooms a connection hears.
+    pub fn subscribe(&self, client_id: &str, rooms: BTreeSet<String>) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(client_id) {
+            connection.rooms = rooms;
+        }
+    }
+
+    pub fn hears(&self, client_id: &str, msg: &Broadcast) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .is_some_and(|connection| connection.hears(msg.room.as_deref(), msg.available_only))
+    }
+
+    /// Connections other than `except` that would hear a ring in `room`.
+    pub fn listeners(&self, room: &str, available_only: bool, except: &str) -> BTreeSet<String> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, connection)| {
+                *id != except && connection.hears(Some(room), available_only)
+            })
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Sends `msg` to each of `client_ids` still connected.
+    ///
+    /// Never waits on a recipient: a connection whose queue is full misses the message, so one
+    /// stalled client can't hold up the sender or everyone else.
+    pub fn send_to<'a>(&self, client_ids: impl IntoIterator<Item = &'a String>, msg: &WsMessage) {
+        let senders: Vec<(&String, mpsc::Sender<Message>)> = {
+            let connections = self.connections.lock().unwrap();
+            client_ids
+                .into_iter()
+                .filter_map(|id| Some((id, connections.get(id)?.link.sender.clone())))
+                .collect()
+        };
+        let text = msg.to_text();
+        for (id, sender) in senders {
+            match sender.try_send(Message::Text(text.clone().into())) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    tracing::warn!("{} is not keeping up, dropping {}", id, event_of(msg))
+                }
+                Err(TrySendError::Closed(_)) => {
+                    tracing::warn!("Could not deliver {} to {}", event_of(msg), id)
+                }
+            }
+        }
+    }
+
+    /// Everyone connected, by display name.
+    pub fn presence(&self) -> Vec<PersonPresence> {
+        let mut people: Vec<PersonPresence> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, connection)| presence_of(id, connection))
+            .collect();
+        people.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        people
+    }
+
+    /// Live connections matching a client ID or, ignoring case, a display name.
+    pub fn find_connections(&self, who: &str) -> Vec<(String, Connection)> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, conn)| *id == who || conn.display_name.eq_ignore_ascii_case(who))
+            .map(|(id, conn)| (id.clone(), conn.clone()))
+            .collect()
+    }
+}
+
+/// Names a message in logs without spelling out what it says.
+fn event_of(msg: &WsMessage) -> String {
+    serde_json::to_value(msg)
+        .ok()
+        .and_then(|value| Some(value.get("event")?.as_str()?.to_string()))
+        .unwrap_or_default()
+}
+
+fn presence_of(client_id: &str, connection: &Connection) -> PersonPresence {
+    PersonPresence {
+        client_id: client_id.to_string(),
+        display_name: connection.display_name.clone(),
+        status: connection.status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// A state with default settings and a throwaway history file, removed on drop.
+    pub struct TestState {
+        pub state: Arc<AppState>,
+        history: PathBuf,
+    }
+
+    impl TestState {
+        pub fn new() -> Self {
+            let history =
+                std::env::temp_dir().join(format!("history-{}.jsonl", uuid::Uuid::new_v4()));
+            let state = AppState::new(
+                Config::default(),
+                Catalog::default(),
+                History::open(&history).unwrap(),
+                IdentityKey::from_bytes(&[1; 32]),
+            );
+            Self { state, history }
+        }
+    }
+
+    impl Drop for TestState {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.history);
+        }
+    }
+
+    pub fn session(client_id: &str) -> Session {
+        Session {
+            client_id: client_id.to_string(),
+            display_name: client_id.to_string(),
+            token_name: None,
+            admin: false,
+            client_version: "test".to_string(),
+            rooms: BTreeSet::from(["general".to_string()]),
+            status: Status::Available,
+            audio_formats: vec!["mp3".to_string()],
+            features: Vec::new(),
+        }
+    }
+
+    fn link() -> Link {
+        Link::new(mpsc::channel(1).0)
+    }
+
+    #[test]
+    fn an_identity_has_one_connection_at_a_time() {
+        let test = TestState::new();
+
+        let alice = test
+            .state
+            .register(&session("alice"), link(), false)
+            .unwrap();
+        assert_eq!(test.state.register(&session("alice"), link(), false), None);
+        assert!(test
+            .state
+            .register(&session("bob"), link(), false)
+            .is_some());
+        assert_eq!(test.state.presence().len(), 2);
+
+        test.state.unregister("alice", alice);
+        assert_eq!(test.state.presence().len(), 1);
+        assert!(test
+            .state
+            .register(&session("alice"), link(), false)
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn taking_an_identity_over_hangs_up_the_previous_connection() {
+        let test = TestState::new();
+        let stale = link();
+
+        let first = test
+            .state
+            .register(&session("alice"), stale.clone(), false)
+            .unwrap();
+        let second = test
+            .state
+            .register(&session("alice"), link(), true)
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), stale.superseded.notified())
+            .await
+            .expect("the previous connection was not told to hang up");
+
+        // The stale connection going away leaves the new one registered.
+        test.state.unregister("alice", first);
+        assert_eq!(test.state.presence().len(), 1);
+        test.state.unregister("alice", second);
+        assert!(test.state.presence().is_empty());
+    }
 }
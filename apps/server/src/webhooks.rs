@@ -0,0 +1,563 @@
+use crate::config::WebhookConfig;
+use common::{RingKind, RingTarget};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Event names a webhook can subscribe to, as found in its payload's `event` field.
+pub const EVENTS: &[&str] = &["ring", "cooldown", "ack", "escalate", "cancel"];
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Something worth telling other tools about.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Ring {
+        ring_id: String,
+        room: String,
+        sender_id: String,
+        sender_name: String,
+        kind: RingKind,
+        sound_hash: Option<String>,
+        target: Option<RingTarget>,
+        message: Option<String>,
+        listeners: usize,
+    },
+    /// A ring refused because its sender rang the room too recently.
+    Cooldown {
+        room: String,
+        sender_id: String,
+        sender_name: String,
+        kind: RingKind,
+        retry_after_secs: u64,
+    },
+    /// Someone answered a ring.
+    Ack {
+        ring_id: String,
+        room: String,
+        sender_name: String,
+        acknowledged_by: String,
+    },
+    /// A ring stopped by its ringer or an admin.
+    Cancel {
+        ring_id: String,
+        room: String,
+        sender_name: String,
+        cancelled_by: String,
+    },
+    /// A ring nobody answered, rung again.
+    Escalate {
+        ring_id: String,
+        room: String,
+        sender_name: String,
+        kind: RingKind,
+        attempt: u32,
+        target: Option<RingTarget>,
+        message: Option<String>,
+        listeners: usize,
+    },
+}
+
+impl WebhookEvent {
+    /// Payload sent as is when a webhook has no template, and the source of its placeholders.
+    fn payload(&self) -> Value {
+        let mut payload = serde_json::to_value(self).expect("WebhookEvent is always serializable");
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        payload["timestamp"] = timestamp.into();
+        payload
+    }
+
+    /// One event of each kind, with optional fields unset and quotes in the text, to try
+    /// templates on.
+    fn samples() -> [WebhookEvent; 5] {
+        let id = || "00000000-0000-0000-0000-000000000000".to_string();
+        let room = || "general".to_string();
+        let name = || "Alice \"A\"".to_string();
+        [
+            WebhookEvent::Ring {
+                ring_id: id(),
+                room: room(),
+                sender_id: id(),
+                sender_name: name(),
+                kind: RingKind::default(),
+                sound_hash: None,
+                target: None,
+                message: None,
+                listeners: 1,
+            },
+            WebhookEvent::Cooldown {
+                room: room(),
+                sender_id: id(),
+                sender_name: name(),
+                kind: RingKind::default(),
+                retry_after_secs: 5,
+            },
+            WebhookEvent::Ack {
+                ring_id: id(),
+                room: room(),
+                sender_name: name(),
+                acknowledged_by: name(),
+            },
+            WebhookEvent::Cancel {
+                ring_id: id(),
+                room: room(),
+                sender_name: name(),
+                cancelled_by: name(),
+            },
+            WebhookEvent::Escalate {
+                ring_id: id(),
+                room: room(),
+                sender_name: name(),
+                kind: RingKind::default(),
+                attempt: 1,
+                target: None,
+                message: None,
+                listeners: 1,
+            },
+        ]
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::Ring { .. } => "ring",
+            WebhookEvent::Cooldown { .. } => "cooldown",
+            WebhookEvent::Ack { .. } => "ack",
+            WebhookEvent::Escalate { .. } => "escalate",
+            WebhookEvent::Cancel { .. } => "cancel",
+        }
+    }
+}
+
+/// Configured webhooks, each delivered in the background with retries.
+pub struct Webhooks {
+    hooks: Vec<WebhookConfig>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<WebhookConfig>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build HTTP client");
+        Self { hooks, client }
+    }
+
+    /// Sends `event` to every webhook that wants it, without waiting for delivery.
+    pub fn fire(&self, event: &WebhookEvent) {
+        let payload = event.payload();
+        for hook in self.hooks.iter().filter(|hook| hook.wants(event.name())) {
+            let body = match &hook.template {
+                Some(template) => match render(template, &payload) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        tracing::error!(
+                            "Webhook {} skipped {} event: {}",
+                            hook.url,
+                            event.name(),
+                            e
+                        );
+                        continue;
+                    }
+                },
+                None => payload.to_string(),
+            };
+            tokio::spawn(deliver(
+                self.client.clone(),
+                hook.clone(),
+                event.name(),
+                body,
+            ));
+        }
+    }
+}
+
+impl WebhookConfig {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+}
+
+/// Checks what can be checked before anything is sent: event names and templates.
+///
+/// Templates are rendered for every event the webhook receives and must name only that event's
+/// fields and give valid JSON, even with its optional fields unset.
+pub fn validate(hooks: &[WebhookConfig]) -> Result<(), String> {
+    let samples = WebhookEvent::samples();
+    for hook in hooks {
+        reqwest::Url::parse(&hook.url).map_err(|e| format!("webhook {}: {}", hook.url, e))?;
+        if let Some(unknown) = hook.events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+            return Err(format!(
+                "webhook {}: unknown event {:?}, expected one of {:?}",
+                hook.url, unknown, EVENTS
+            ));
+        }
+        if let Some(template) = &hook.template {
+            for sample in samples.iter().filter(|sample| hook.wants(sample.name())) {
+                let body = render(template, &sample.payload()).map_err(|e| {
+                    format!("webhook {}: {} for {} events", hook.url, e, sample.name())
+                })?;
+                serde_json::from_str::<Value>(&body).map_err(|e| {
+                    format!(
+                        "webhook {}: template is not valid JSON for {} events: {}",
+                        hook.url,
+                        sample.name(),
+                        e
+                    )
+                })?;
+            }
+        }
+        if hook.max_attempts == 0 {
+            return Err(format!(
+                "webhook {}: max_attempts must be at least 1",
+                hook.url
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `{{field}}` with the payload's top-level `field`.
+///
+/// Strings are JSON-escaped but not quoted, so they can sit inside a template's own string
+/// literals; other values are written as JSON, except unset ones which become empty. Fails on
+/// fields the event doesn't have.
+fn render(template: &str, payload: &Value) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let field = rest[start + 2..start + 2 + len].trim();
+        match payload.get(field) {
+            Some(Value::String(s)) => {
+                let quoted = Value::String(s.clone()).to_string();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Some(Value::Null) => {}
+            Some(other) => out.push_str(&other.to_string()),
+            None => return Err(format!("unknown placeholder {{{{{}}}}}", field)),
+        }
+        rest = &rest[start + 2 + len + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts `body`, retrying with exponential backoff on network errors, 429 and 5xx.
+async fn deliver(client: reqwest::Client, hook: WebhookConfig, event: &'static str, body: String) {
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 1..=hook.max_attempts {
+        let mut request = client
+            .post(&hook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Sonnerie-Event", event)
+            .body(body.clone());
+        if let Some(secret) = &hook.secret {
+            request = request.header("X-Sonnerie-Signature", sign(secret, &body));
+        }
+
+        let retryable = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                tracing::debug!("Webhook {} accepted {} event", hook.url, event);
+                return;
+            }
+            Ok(response) => {
+                let status = response.status();
+                tracing::warn!(
+                    "Webhook {} answered {} to {} event",
+                    hook.url,
+                    status,
+                    event
+                );
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                tracing::warn!("Webhook {} failed for {} event: {}", hook.url, event, e);
+                true
+            }
+        };
+        if !retryable || attempt == hook.max_attempts {
+            break;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+    tracing::error!("Giving up on webhook {} for {} event", hook.url, event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    /// Requests received by a [`listen`] endpoint, with the headers it was sent.
+    type Received = mpsc::UnboundedReceiver<(HeaderMap, String)>;
+
+    struct Endpoint {
+        statuses: Mutex<VecDeque<StatusCode>>,
+        received: mpsc::UnboundedSender<(HeaderMap, String)>,
+    }
+
+    /// Serves a local webhook answering `statuses` in turn, then 200.
+    async fn listen(statuses: &[StatusCode]) -> (String, Received) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let endpoint = Arc::new(Endpoint {
+            statuses: Mutex::new(statuses.iter().copied().collect()),
+            received: tx,
+        });
+        let app =
+            Router::new()
+                .route(
+                    "/hook",
+                    post(
+                        |State(endpoint): State<Arc<Endpoint>>,
+                         headers: HeaderMap,
+                         body: String| async move {
+                            let _ = endpoint.received.send((headers, body));
+                            endpoint
+                                .statuses
+                                .lock()
+                                .unwrap()
+                                .pop_front()
+                                .unwrap_or(StatusCode::OK)
+                        },
+                    ),
+                )
+                .with_state(endpoint);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, rx)
+    }
+
+    async fn next(received: &mut Received) -> (HeaderMap, String) {
+        tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("webhook was not called")
+            .unwrap()
+    }
+
+    fn hook(url: &str) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            secret: None,
+            events: Vec::new(),
+            template: None,
+            max_attempts: 3,
+        }
+    }
+
+    fn ring(message: Option<&str>) -> WebhookEvent {
+        WebhookEvent::Ring {
+            ring_id: "r1".to_string(),
+            room: "reception".to_string(),
+            sender_id: "c1".to_string(),
+            sender_name: "Alice \"A\"".to_string(),
+            kind: RingKind::Delivery,
+            sound_hash: None,
+            target: None,
+            message: message.map(str::to_string),
+            listeners: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn signs_the_body_with_the_secret() {
+        let (url, mut received) = listen(&[]).await;
+        let hook = WebhookConfig {
+            secret: Some("s3cret".to_string()),
+            ..hook(&url)
+        };
+
+        deliver(
+            reqwest::Client::new(),
+            hook,
+            "ring",
+            "{\"a\":1}".to_string(),
+        )
+        .await;
+        let (headers, body) = next(&mut received).await;
+
+        assert_eq!(body, "{\"a\":1}");
+        assert_eq!(headers["x-sonnerie-event"], "ring");
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(body.as_bytes());
+        let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(headers["x-sonnerie-signature"], expected.as_str());
+    }
+
+    #[tokio::test]
+    async fn unsigned_without_a_secret() {
+        let (url, mut received) = listen(&[]).await;
+
+        deliver(reqwest::Client::new(), hook(&url), "ring", "{}".to_string()).await;
+        let (headers, _) = next(&mut received).await;
+
+        assert!(!headers.contains_key("x-sonnerie-signature"));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_until_accepted() {
+        let (url, mut received) = listen(&[StatusCode::SERVICE_UNAVAILABLE]).await;
+
+        let started = std::time::Instant::now();
+        deliver(reqwest::Client::new(), hook(&url), "ring", "{}".to_string()).await;
+
+        next(&mut received).await;
+        next(&mut received).await;
+        assert!(received.try_recv().is_err());
+        assert!(started.elapsed() >= FIRST_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (url, mut received) = listen(&[StatusCode::INTERNAL_SERVER_ERROR; 3]).await;
+        let limited = WebhookConfig {
+            max_attempts: 2,
+            ..hook(&url)
+        };
+
+        deliver(reqwest::Client::new(), limited, "ring", "{}".to_string()).await;
+
+        next(&mut received).await;
+        next(&mut received).await;
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, mut received) = listen(&[StatusCode::BAD_REQUEST]).await;
+
+        deliver(reqwest::Client::new(), hook(&url), "ring", "{}".to_string()).await;
+        next(&mut received).await;
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fire_sends_the_rendered_template_to_subscribers_only() {
+        let (url, mut received) = listen(&[]).await;
+        let (other_url, mut other) = listen(&[]).await;
+        let webhooks = Webhooks::new(vec![
+            WebhookConfig {
+                events: vec!["ring".to_string()],
+                template: Some(
+                    r#"{"text": "{{sender_name}} ({{room}}) : {{message}}", "n": {{listeners}}}"#
+                        .to_string(),
+                ),
+                ..hook(&url)
+            },
+            WebhookConfig {
+                events: vec!["ack".to_string()],
+                ..hook(&other_url)
+            },
+        ]);
+
+        webhooks.fire(&ring(Some("colis \"urgent\"")));
+        let (headers, body) = next(&mut received).await;
+
+        assert_eq!(headers["content-type"], "application/json");
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"text": "Alice \"A\" (reception) : colis \"urgent\"", "n": 2})
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(other.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn fire_sends_the_event_json_without_a_template() {
+        let (url, mut received) = listen(&[]).await;
+        let webhooks = Webhooks::new(vec![hook(&url)]);
+
+        webhooks.fire(&ring(None));
+        let (_, body) = next(&mut received).await;
+
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["event"], "ring");
+        assert_eq!(body["room"], "reception");
+        assert_eq!(body["message"], Value::Null);
+        assert!(body["timestamp"].is_u64());
+    }
+
+    #[test]
+    fn render_escapes_strings_and_refuses_unknown_fields() {
+        let payload = ring(Some("a \"b\"\nc")).payload();
+
+        assert_eq!(
+            render(r#"{"m": "{{ message }}", "n": {{listeners}}}"#, &payload).unwrap(),
+            r#"{"m": "a \"b\"\nc", "n": 2}"#
+        );
+        assert_eq!(
+            render(r#"{"m": "{{sound_hash}}"}"#, &payload).unwrap(),
+            r#"{"m": ""}"#
+        );
+        assert!(render(r#"{"m": "{{acknowledged_by}}"}"#, &payload).is_err());
+        assert!(render(r#"{"m": "{{nope}}"}"#, &payload).is_err());
+    }
+
+    #[test]
+    fn validate_tries_templates_on_every_subscribed_event() {
+        let template = |template: &str, events: &[&str]| WebhookConfig {
+            template: Some(template.to_string()),
+            events: events.iter().map(|e| e.to_string()).collect(),
+            ..hook("http://localhost/hook")
+        };
+
+        assert!(validate(&[template(r#"{"t": "{{sender_name}} {{room}}"}"#, &[])]).is_ok());
+        assert!(validate(&[template(r#"{"t": "{{message}}"}"#, &["ring", "escalate"])]).is_ok());
+        // Only ring and escalate events have a message.
+        assert!(validate(&[template(r#"{"t": "{{message}}"}"#, &[])]).is_err());
+        assert!(validate(&[template(r#"{"by": "{{acknowledged_by}}"}"#, &["ack"])]).is_ok());
+        assert!(validate(&[template(
+            r#"{"by": "{{acknowledged_by}}"}"#,
+            &["ack", "cancel"]
+        )])
+        .is_err());
+        // Unset fields render empty, which breaks JSON outside a string.
+        assert!(validate(&[template(r#"{"t": {{message}}}"#, &["ring"])]).is_err());
+        // Strings must sit inside the template's quotes.
+        assert!(validate(&[template(r#"{"t": {{room}}}"#, &["ring"])]).is_err());
+        assert!(validate(&[template(r#"{"n": {{listeners}}}"#, &["ring"])]).is_ok());
+    }
+
+    #[test]
+    fn validate_refuses_bad_urls_events_and_attempts() {
+        assert!(validate(&[hook("not a url")]).is_err());
+        assert!(validate(&[WebhookConfig {
+            events: vec!["rung".to_string()],
+            ..hook("http://localhost/hook")
+        }])
+        .is_err());
+        assert!(validate(&[WebhookConfig {
+            max_attempts: 0,
+            ..hook("http://localhost/hook")
+        }])
+        .is_err());
+        assert!(validate(&[hook("http://localhost/hook")]).is_ok());
+    }
+}
@@ -0,0 +1,639 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the wire protocol spoken by this build.
+///
+/// Bump it whenever a change would break older peers.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Every JSON message exchanged over the `/ws` socket.
+///
+/// On the wire this is `{"event": "<name>", "data": { ... }}`. Asset content
+/// travels separately as binary frames, see [`crate::transfer`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum WsMessage {
+    Hello(Hello),
+    Welcome(Welcome),
+    Rejected(Rejected),
+    RingBell(RingBell),
+    RingSent(RingSent),
+    CancelRing(CancelRing),
+    RingCancelled(RingCancelled),
+    Ack(Ack),
+    Acknowledged(Acknowledged),
+    Subscribe(RoomSubscription),
+    Subscribed(RoomSubscription),
+    SetStatus(SetStatus),
+    Presence(Presence),
+    Joined(PersonPresence),
+    Left(PersonPresence),
+    StatusChanged(PersonPresence),
+    History(RingHistory),
+    Manifest(Manifest),
+    FetchAssets(FetchAssets),
+    Error(ProtocolError),
+}
+
+impl WsMessage {
+    /// Event names understood by this version of the protocol, in variant order; a test keeps
+    /// it in step with the enum.
+    const EVENTS: &'static [&'static str] = &[
+        "hello",
+        "welcome",
+        "rejected",
+        "ring_bell",
+        "ring_sent",
+        "cancel_ring",
+        "ring_cancelled",
+        "ack",
+        "acknowledged",
+        "subscribe",
+        "subscribed",
+        "set_status",
+        "presence",
+        "joined",
+        "left",
+        "status_changed",
+        "history",
+        "manifest",
+        "fetch_assets",
+        "error",
+    ];
+
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        Self::Rejected(Rejected {
+            reason: reason.into(),
+        })
+    }
+
+    pub fn ring_bell(room: Option<String>, target: Option<RingTarget>, kind: RingKind) -> Self {
+        Self::RingBell(RingBell {
+            room,
+            target,
+            kind,
+            ..Default::default()
+        })
+    }
+
+    pub fn cancel_ring(ring_id: impl Into<String>) -> Self {
+        Self::CancelRing(CancelRing {
+            ring_id: ring_id.into(),
+        })
+    }
+
+    pub fn ack(ring_id: impl Into<String>) -> Self {
+        Self::Ack(Ack {
+            ring_id: ring_id.into(),
+        })
+    }
+
+    pub fn set_status(status: Status) -> Self {
+        Self::SetStatus(SetStatus { status })
+    }
+
+    pub fn manifest(assets: Vec<AssetEntry>) -> Self {
+        Self::Manifest(Manifest { assets })
+    }
+
+    pub fn fetch_assets(assets: Vec<AssetFetch>) -> Self {
+        Self::FetchAssets(FetchAssets { assets })
+    }
+
+    pub fn error(error: ProtocolError) -> Self {
+        Self::Error(error)
+    }
+
+    /// Parses a text frame, telling apart broken JSON, unknown events and bad payloads.
+    pub fn from_text(text: &str) -> Result<Self, ProtocolError> {
+        let mut value: serde_json::Value =
+            serde_json::from_str(text).map_err(|e| ProtocolError::malformed(e.to_string()))?;
+
+        let event = value
+            .get("event")
+            .and_then(|event| event.as_str())
+            .ok_or_else(|| ProtocolError::malformed("missing \"event\" field"))?
+            .to_string();
+
+        if !Self::EVENTS.contains(&event.as_str()) {
+            return Err(ProtocolError::unknown_event(&event));
+        }
+
+        // Older peers send `"data": null` (or nothing) for events without a payload.
+        if let Some(object) = value.as_object_mut() {
+            if object.get("data").is_none_or(|data| data.is_null()) {
+                object.insert("data".to_string(), serde_json::json!({}));
+            }
+        }
+
+        serde_json::from_value(value).map_err(|e| ProtocolError::invalid_payload(&event, e))
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("WsMessage is always serializable")
+    }
+}
+
+/// First message a client sends after connecting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub client_version: String,
+    /// Identity from a previous session; the server keeps it only with its secret, and unless
+    /// it is already in use.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Secret the server issued along with `client_id`, proving it is ours.
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Name shown to others when this client rings.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Rooms to hear rings from; empty means the server's default room.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Availability to start with, so it survives reconnections.
+    #[serde(default)]
+    pub status: Status,
+    /// File extensions the client can play, e.g. `["mp3", "wav"]`.
+    pub audio_formats: Vec<String>,
+    /// Optional features the client knows how to use.
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// Server answer to an accepted [`Hello`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    pub protocol_version: u32,
+    pub server_version: String,
+    /// Identity the server assigned to this connection, used as `sender_id` in its rings.
+    pub client_id: String,
+    /// Secret to present with `client_id` in later hellos to get the same identity back.
+    #[serde(default)]
+    pub client_secret: String,
+    /// Rooms this client is subscribed to, after dropping unknown ones.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Features both sides support; anything else must not be used on this connection.
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// Sent instead of [`Welcome`] right before the server closes the connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rejected {
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RingBell {
+    /// Server-assigned ID, to answer the ring with [`Ack`]. Left out by clients.
+    #[serde(default)]
+    pub ring_id: Option<String>,
+    /// Room the ring is for; the server's default room when a client leaves it out.
+    #[serde(default)]
+    pub room: Option<String>,
+    /// Rings only these people, whatever rooms they listen to; everyone in the room otherwise.
+    #[serde(default)]
+    pub target: Option<RingTarget>,
+    #[serde(default)]
+    pub kind: RingKind,
+    /// Skips people who are busy or away, unless the kind is [`Urgency::Critical`].
+    #[serde(default)]
+    pub available_only: bool,
+    /// Identity of the ringer, stamped by the server; whatever a client puts here is ignored.
+    #[serde(default)]
+    pub sender_id: Option<String>,
+    /// Display name of the ringer, also filled in by the server.
+    #[serde(default)]
+    pub sender_name: Option<String>,
+    /// Hash of the sound the server picked for this ring.
+    #[serde(default)]
+    pub sound_hash: Option<String>,
+    /// Short note from the ringer, e.g. "colis pour Bob"; cleaned up and length-checked by the
+    /// server.
+    #[serde(default)]
+    pub message: Option<String>,
+    /// 0 for the first ring, then counting the server's re-rings while nobody answers.
+    #[serde(default)]
+    pub attempt: u32,
+}
+
+/// Told to a client once its own ring went out, so it can cancel it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RingSent {
+    pub ring_id: String,
+    pub room: String,
+    pub listeners: usize,
+}
+
+/// Request to stop a ring still in progress; only its ringer, or an admin, may send it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CancelRing {
+    pub ring_id: String,
+}
+
+/// Told to the ringer and everyone the ring reached once it is cancelled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RingCancelled {
+    pub ring_id: String,
+    /// Display name of whoever cancelled it.
+    pub by: String,
+}
+
+/// "I'm coming": a client answering a ring it received.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ack {
+    pub ring_id: String,
+}
+
+/// Told to the ringer and everyone the ring reached once someone answers it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Acknowledged {
+    pub ring_id: String,
+    pub client_id: String,
+    pub display_name: String,
+}
+
+/// Who a ring is for, when not the whole room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RingTarget {
+    /// One person, by client ID or display name.
+    Person(String),
+    /// A group defined in the server configuration.
+    Group(String),
+}
+
+/// What a ring is about, picking its sounds, text and urgency.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RingKind {
+    #[default]
+    Doorbell,
+    Delivery,
+    Lunch,
+    Meeting,
+    Emergency,
+}
+
+impl RingKind {
+    pub const ALL: [RingKind; 5] = [
+        RingKind::Doorbell,
+        RingKind::Delivery,
+        RingKind::Lunch,
+        RingKind::Meeting,
+        RingKind::Emergency,
+    ];
+
+    /// Name used on the wire and in configuration files.
+    pub fn name(self) -> &'static str {
+        match self {
+            RingKind::Doorbell => "doorbell",
+            RingKind::Delivery => "delivery",
+            RingKind::Lunch => "lunch",
+            RingKind::Meeting => "meeting",
+            RingKind::Emergency => "emergency",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn urgency(self) -> Urgency {
+        match self {
+            RingKind::Lunch => Urgency::Low,
+            RingKind::Doorbell | RingKind::Delivery | RingKind::Meeting => Urgency::Normal,
+            RingKind::Emergency => Urgency::Critical,
+        }
+    }
+}
+
+/// How hard a ring insists on being heard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Urgency {
+    Low,
+    Normal,
+    /// Reaches people who are busy or away, and plays even when they asked for quiet.
+    Critical,
+}
+
+/// How willing someone is to be rung right now.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Available,
+    Busy,
+    Away,
+}
+
+/// Client request to change its own [`Status`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetStatus {
+    pub status: Status,
+}
+
+/// One connected person, as announced by `joined`, `left` and `status_changed`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PersonPresence {
+    pub client_id: String,
+    pub display_name: String,
+    pub status: Status,
+}
+
+/// Everyone connected, sent once after the handshake; later changes come as events.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Presence {
+    pub people: Vec<PersonPresence>,
+}
+
+/// Latest rings in a client's rooms, sent once after the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RingHistory {
+    /// Most recent first.
+    pub rings: Vec<RingSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RingSummary {
+    pub room: String,
+    pub sender_name: String,
+    /// Unix time, in seconds.
+    pub at: i64,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// Rooms a client wants to hear (`subscribe`, replacing the previous set) or,
+/// in the server's `subscribed` answer, the ones it actually got.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSubscription {
+    pub rooms: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AssetEntry {
+    pub name: String,
+    /// Hex SHA-256 of the file content.
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Full list of assets the server publishes; anything else on a client is stale.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub assets: Vec<AssetEntry>,
+}
+
+/// Client request for the assets it is missing from the last [`Manifest`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchAssets {
+    pub assets: Vec<AssetFetch>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssetFetch {
+    pub name: String,
+    pub hash: String,
+    /// Bytes already received by an interrupted download.
+    #[serde(default)]
+    pub offset: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The frame is not JSON or has no `event` field.
+    Malformed,
+    /// The `event` field names a message this peer does not know.
+    UnknownEvent,
+    /// The event is known but its `data` does not match the expected payload.
+    InvalidPayload,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProtocolError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn malformed(message: impl Into<String>) -> Self {
+        Self {
+            kind: ErrorKind::Malformed,
+            message: message.into(),
+        }
+    }
+
+    pub fn unknown_event(event: &str) -> Self {
+        Self {
+            kind: ErrorKind::UnknownEvent,
+            message: format!("unknown event \"{}\"", event),
+        }
+    }
+
+    pub fn invalid_payload(event: &str, error: impl fmt::Display) -> Self {
+        Self {
+            kind: ErrorKind::InvalidPayload,
+            message: format!("invalid payload for \"{}\": {}", event, error),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One message of each kind, the way peers send them.
+    fn every_message() -> Vec<WsMessage> {
+        let person = PersonPresence {
+            client_id: "alice".to_string(),
+            display_name: "Alice".to_string(),
+            status: Status::Available,
+        };
+        let rooms = RoomSubscription {
+            rooms: vec!["general".to_string()],
+        };
+        vec![
+            WsMessage::Hello(Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_version: "1.0.0".to_string(),
+                client_id: None,
+                client_secret: None,
+                display_name: None,
+                rooms: Vec::new(),
+                status: Status::Available,
+                audio_formats: vec!["mp3".to_string()],
+                features: Vec::new(),
+            }),
+            WsMessage::Welcome(Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                server_version: "1.0.0".to_string(),
+                client_id: "alice".to_string(),
+                client_secret: "00".to_string(),
+                rooms: Vec::new(),
+                features: Vec::new(),
+            }),
+            WsMessage::rejected("too old"),
+            WsMessage::ring_bell(None, None, RingKind::Doorbell),
+            WsMessage::RingSent(RingSent {
+                ring_id: "r1".to_string(),
+                room: "general".to_string(),
+                listeners: 2,
+            }),
+            WsMessage::cancel_ring("r1"),
+            WsMessage::RingCancelled(RingCancelled {
+                ring_id: "r1".to_string(),
+                by: "Alice".to_string(),
+            }),
+            WsMessage::ack("r1"),
+            WsMessage::Acknowledged(Acknowledged {
+                ring_id: "r1".to_string(),
+                client_id: "alice".to_string(),
+                display_name: "Alice".to_string(),
+            }),
+            WsMessage::Subscribe(rooms.clone()),
+            WsMessage::Subscribed(rooms),
+            WsMessage::set_status(Status::Busy),
+            WsMessage::Presence(Presence {
+                people: vec![person.clone()],
+            }),
+            WsMessage::Joined(person.clone()),
+            WsMessage::Left(person.clone()),
+            WsMessage::StatusChanged(person),
+            WsMessage::History(RingHistory { rings: Vec::new() }),
+            WsMessage::manifest(Vec::new()),
+            WsMessage::fetch_assets(Vec::new()),
+            WsMessage::error(ProtocolError::malformed("oops")),
+        ]
+    }
+
+    /// Position of the message's variant in the enum; this stops compiling when a variant is
+    /// added, which is the reminder to give it a sample above.
+    fn variant(message: &WsMessage) -> usize {
+        match message {
+            WsMessage::Hello(_) => 0,
+            WsMessage::Welcome(_) => 1,
+            WsMessage::Rejected(_) => 2,
+            WsMessage::RingBell(_) => 3,
+            WsMessage::RingSent(_) => 4,
+            WsMessage::CancelRing(_) => 5,
+            WsMessage::RingCancelled(_) => 6,
+            WsMessage::Ack(_) => 7,
+            WsMessage::Acknowledged(_) => 8,
+            WsMessage::Subscribe(_) => 9,
+            WsMessage::Subscribed(_) => 10,
+            WsMessage::SetStatus(_) => 11,
+            WsMessage::Presence(_) => 12,
+            WsMessage::Joined(_) => 13,
+            WsMessage::Left(_) => 14,
+            WsMessage::StatusChanged(_) => 15,
+            WsMessage::History(_) => 16,
+            WsMessage::Manifest(_) => 17,
+            WsMessage::FetchAssets(_) => 18,
+            WsMessage::Error(_) => 19,
+        }
+    }
+
+    #[test]
+    fn events_lists_every_variant() {
+        let messages = every_message();
+        let variants: Vec<usize> = messages.iter().map(variant).collect();
+        assert_eq!(variants, (0..messages.len()).collect::<Vec<_>>());
+
+        let mut events = Vec::new();
+        for message in &messages {
+            let text = message.to_text();
+            let parsed = WsMessage::from_text(&text).unwrap_or_else(|e| panic!("{}: {}", text, e));
+            assert_eq!(variant(&parsed), variant(message));
+            let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+            events.push(value["event"].as_str().unwrap().to_string());
+        }
+        assert_eq!(events, WsMessage::EVENTS);
+    }
+
+    #[test]
+    fn from_text_round_trips_to_text() {
+        let sent = WsMessage::ring_bell(Some("reception".to_string()), None, RingKind::Lunch);
+        match WsMessage::from_text(&sent.to_text()) {
+            Ok(WsMessage::RingBell(ring)) => {
+                assert_eq!(ring.room.as_deref(), Some("reception"));
+                assert_eq!(ring.kind, RingKind::Lunch);
+                assert_eq!(ring.target, None);
+            }
+            other => panic!("unexpected parse: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_text_accepts_missing_or_null_data() {
+        for text in [
+            r#"{"event":"ring_bell"}"#,
+            r#"{"event":"ring_bell","data":null}"#,
+        ] {
+            assert!(
+                matches!(WsMessage::from_text(text), Ok(WsMessage::RingBell(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn from_text_rejects_broken_json() {
+        let error = WsMessage::from_text("{\"event\":").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn from_text_rejects_missing_event() {
+        for text in [r#"{"data":{}}"#, r#"{"event":3}"#, "[]"] {
+            let error = WsMessage::from_text(text).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Malformed, "{}", text);
+        }
+    }
+
+    #[test]
+    fn from_text_rejects_unknown_event() {
+        let error = WsMessage::from_text(r#"{"event":"dance","data":{}}"#).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnknownEvent);
+        assert!(error.message.contains("dance"));
+    }
+
+    #[test]
+    fn from_text_rejects_bad_payload() {
+        let error = WsMessage::from_text(r#"{"event":"ack","data":{"ring_id":42}}"#).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::InvalidPayload);
+        assert!(error.message.contains("ack"));
+    }
+
+    #[test]
+    fn other_events_round_trip() {
+        let messages = [
+            WsMessage::ack("r"),
+            WsMessage::cancel_ring("r"),
+            WsMessage::set_status(Status::Busy),
+            WsMessage::manifest(Vec::new()),
+            WsMessage::fetch_assets(Vec::new()),
+            WsMessage::rejected("no"),
+            WsMessage::error(ProtocolError::malformed("x")),
+        ];
+        for message in messages {
+            let text = message.to_text();
+            assert!(WsMessage::from_text(&text).is_ok(), "{}", text);
+        }
+    }
+}
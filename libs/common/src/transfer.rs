@@ -0,0 +1,176 @@
+//! Binary framing for asset transfers.
+//!
+//! Assets travel as binary WebSocket frames laid out as:
+//!
+//! ```text
+//! +----------------------+----------------------+-----------------+
+//! | header length (u32)  | header (JSON, UTF-8) | payload bytes   |
+//! +----------------------+----------------------+-----------------+
+//! ```
+//!
+//! The header length is big-endian. The payload is one chunk of the file,
+//! starting at `offset`; files are sent as a sequence of at most
+//! [`CHUNK_SIZE`] byte chunks so an interrupted download can resume where it
+//! stopped.
+
+use crate::ProtocolError;
+use serde::{Deserialize, Serialize};
+
+/// Maximum payload carried by a single asset frame.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Upper bound on the header size, so a corrupt prefix can't make us allocate wildly.
+const MAX_HEADER_LEN: usize = 16 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AssetHeader {
+    pub filename: String,
+    /// Hex SHA-256 of the complete file.
+    pub hash: String,
+    /// Size of the complete file.
+    pub total: u64,
+    /// Position of this chunk in the file.
+    pub offset: u64,
+    /// Number of payload bytes following the header.
+    pub length: u64,
+}
+
+impl AssetHeader {
+    /// True when this chunk completes the file.
+    pub fn is_last(&self) -> bool {
+        self.offset + self.length >= self.total
+    }
+}
+
+pub fn encode_asset(header: &AssetHeader, payload: &[u8]) -> Vec<u8> {
+    let header = serde_json::to_vec(header).expect("AssetHeader is always serializable");
+    let mut frame = Vec::with_capacity(4 + header.len() + payload.len());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Splits a binary frame into its header and payload, checking the advertised length.
+pub fn decode_asset(frame: &[u8]) -> Result<(AssetHeader, &[u8]), ProtocolError> {
+    let (len_bytes, rest) = frame
+        .split_first_chunk::<4>()
+        .ok_or_else(|| ProtocolError::malformed("binary frame too short"))?;
+    let header_len = u32::from_be_bytes(*len_bytes) as usize;
+    if header_len > MAX_HEADER_LEN || header_len > rest.len() {
+        return Err(ProtocolError::malformed(format!(
+            "invalid asset header length {}",
+            header_len
+        )));
+    }
+
+    let (header, payload) = rest.split_at(header_len);
+    let header: AssetHeader =
+        serde_json::from_slice(header).map_err(|e| ProtocolError::invalid_payload("asset", e))?;
+    let end = header.offset.checked_add(header.length);
+    if header.length != payload.len() as u64 || end.is_none_or(|end| end > header.total) {
+        return Err(ProtocolError::invalid_payload(
+            "asset",
+            format!(
+                "header announces {} bytes at offset {} of {} but frame carries {}",
+                header.length,
+                header.offset,
+                header.total,
+                payload.len()
+            ),
+        ));
+    }
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+
+    fn header(offset: u64, length: u64, total: u64) -> AssetHeader {
+        AssetHeader {
+            filename: "cloche.mp3".to_string(),
+            hash: "ab".repeat(32),
+            total,
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn decode_asset_reverses_encode_asset() {
+        let sent = header(4, 3, 7);
+        let frame = encode_asset(&sent, b"abc");
+        let (received, payload) = decode_asset(&frame).unwrap();
+        assert_eq!(received, sent);
+        assert_eq!(payload, b"abc");
+        assert!(received.is_last());
+    }
+
+    #[test]
+    fn decode_asset_accepts_empty_payload() {
+        let frame = encode_asset(&header(0, 0, 0), b"");
+        let (received, payload) = decode_asset(&frame).unwrap();
+        assert!(payload.is_empty());
+        assert!(received.is_last());
+    }
+
+    #[test]
+    fn decode_asset_rejects_short_frames() {
+        for frame in [&b""[..], b"\0\0\0"] {
+            assert_eq!(decode_asset(frame).unwrap_err().kind, ErrorKind::Malformed);
+        }
+    }
+
+    #[test]
+    fn decode_asset_rejects_header_length_past_frame() {
+        let mut frame = encode_asset(&header(0, 3, 3), b"abc");
+        let past_end = frame.len() as u32;
+        frame[..4].copy_from_slice(&past_end.to_be_bytes());
+        assert_eq!(decode_asset(&frame).unwrap_err().kind, ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn decode_asset_rejects_oversized_header_length() {
+        let mut frame = (MAX_HEADER_LEN as u32 + 1).to_be_bytes().to_vec();
+        frame.resize(4 + MAX_HEADER_LEN + 1, b' ');
+        assert_eq!(decode_asset(&frame).unwrap_err().kind, ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn decode_asset_rejects_bad_header_json() {
+        let mut frame = 2u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(b"{}");
+        assert_eq!(
+            decode_asset(&frame).unwrap_err().kind,
+            ErrorKind::InvalidPayload
+        );
+    }
+
+    #[test]
+    fn decode_asset_rejects_length_mismatch() {
+        let frame = encode_asset(&header(0, 5, 5), b"abc");
+        assert_eq!(
+            decode_asset(&frame).unwrap_err().kind,
+            ErrorKind::InvalidPayload
+        );
+    }
+
+    #[test]
+    fn decode_asset_rejects_chunk_past_total() {
+        for sent in [header(6, 3, 8), header(u64::MAX, 3, u64::MAX)] {
+            let frame = encode_asset(&sent, b"abc");
+            assert_eq!(
+                decode_asset(&frame).unwrap_err().kind,
+                ErrorKind::InvalidPayload
+            );
+        }
+    }
+
+    #[test]
+    fn is_last_only_for_final_chunk() {
+        assert!(!header(0, CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64).is_last());
+        assert!(header(CHUNK_SIZE as u64, 10, CHUNK_SIZE as u64 + 10).is_last());
+    }
+}